@@ -0,0 +1,235 @@
+use log::{info,warn};
+use rayon::prelude::*;
+use std::collections::BTreeSet;
+
+use crate::{
+    common::*,
+    math::*,
+    config,
+    disk::DiskIterator,
+    gerber::NetInfos,
+    grid::Grid,
+    registration
+};
+
+/// Maximum number of through-hole pads used as anchors
+const ANCHORS_MAX : usize = 200;
+
+pub struct LayerAlignment {
+    /// Offset relative to the reference layer, in pixels (dy,dx)
+    pub offset:(Real,Real),
+    /// Peak correlation relative to the autocorrelation of the
+    /// reference layer
+    pub correlation:Real,
+    /// Mean offset of pad centroids from their Gerber positions,
+    /// in pixels (dy,dx)
+    pub gerber_offset:Option<(Real,Real)>
+}
+
+/// Flash points present on every layer, such as through-hole pads
+/// and vias
+pub fn common_points(net_infos:&[NetInfos])->Vec<(Real,Real)> {
+    let key = |x:Real,y:Real| ((x*1e3).round() as i64,(y*1e3).round() as i64);
+    let mut common : Option<BTreeSet<(i64,i64)>> = None;
+    for ni in net_infos.iter() {
+	let keys : BTreeSet<(i64,i64)> = ni.index.values()
+	    .flat_map(|points| points.iter().map(|p| key(p.x,p.y)))
+	    .collect();
+	common = Some(match common {
+	    None => keys,
+	    Some(c) => c.intersection(&keys).cloned().collect()
+	});
+    }
+    common.unwrap_or_default()
+	.into_iter()
+	.map(|(x,y)| (x as Real * 1e-3,y as Real * 1e-3))
+	.collect()
+}
+
+fn correlation(layers:&Array2<u16>,ref_mask:u16,mask:u16,
+	       windows:&[Vec<(usize,usize)>],dy:isize,dx:isize)->usize {
+    let (ny,nx) = layers.dim();
+    let mut n = 0;
+    for w in windows.iter() {
+	for &(iy,ix) in w.iter() {
+	    if layers[[iy,ix]] & ref_mask == 0 {
+		continue;
+	    }
+	    let jy = iy as isize + dy;
+	    let jx = ix as isize + dx;
+	    if 0 <= jy && jy < ny as isize && 0 <= jx && jx < nx as isize &&
+		layers[[jy as usize,jx as usize]] & mask != 0 {
+		n += 1;
+	    }
+	}
+    }
+    n
+}
+
+/// Vertex of the parabola through three equally spaced samples
+fn parabolic(fm:Real,f0:Real,fp:Real)->Real {
+    let d = fm - 2.0*f0 + fp;
+    if d < 0.0 {
+	(0.5*(fm - fp)/d).clamp(-0.5,0.5)
+    } else {
+	0.0
+    }
+}
+
+/// Measures the offset of every layer relative to the first one by
+/// cross-correlating the copper around pads common to all layers
+pub fn check(layers:&Array2<u16>,nlay:usize,grid:&Grid,net_infos:&[NetInfos],
+	     params:&config::Alignment)->Res<Vec<LayerAlignment>> {
+    let points = common_points(net_infos);
+    if points.is_empty() {
+	return Err(error("No flash points common to all layers"));
+    }
+    let stride = points.len().div_ceil(ANCHORS_MAX);
+    let anchors : Vec<(Real,Real)> = points.iter()
+	.filter(|&&(x,y)| grid.pixel(x,y).is_some())
+	.step_by(stride.max(1))
+	.cloned()
+	.collect();
+    info!("Checking alignment using {} of {} common flash points",
+	  anchors.len(),points.len());
+
+    let (ny,nx) = layers.dim();
+    let r = params.radius / grid.delta;
+    let windows : Vec<Vec<(usize,usize)>> = anchors.iter()
+	.map(|&(x,y)| {
+	    let (iyf,ixf) = grid.index(x,y);
+	    DiskIterator::new(iyf + 0.5,ixf + 0.5,r,
+			      0,ny as isize - 1,
+			      0,nx as isize - 1)
+		.map(|(iy,ix)| (iy as usize,ix as usize))
+		.collect()
+	})
+	.collect();
+
+    let s = (params.max_shift / grid.delta).ceil() as isize;
+    let auto = correlation(layers,1,1,&windows,0,0).max(1);
+    let mut result = Vec::new();
+    for ilay in 0..nlay {
+	let mask = 1 << ilay;
+	let scores : Vec<usize> = (-s..=s).into_par_iter()
+	    .flat_map(|dy| (-s..=s).into_par_iter().map(move |dx| (dy,dx)))
+	    .map(|(dy,dx)| correlation(layers,1,mask,&windows,dy,dx))
+	    .collect();
+	let n = (2*s + 1) as usize;
+	let score = |dy:isize,dx:isize| {
+	    scores[(dy + s) as usize * n + (dx + s) as usize] as Real
+	};
+	let (ibest,_) = scores.iter().enumerate()
+	    .max_by_key(|&(i,&v)| (v,std::cmp::Reverse(i)))
+	    .unwrap();
+	let dy = (ibest / n) as isize - s;
+	let dx = (ibest % n) as isize - s;
+	let f0 = score(dy,dx);
+	let fy = if dy.abs() < s {
+	    parabolic(score(dy - 1,dx),f0,score(dy + 1,dx))
+	} else {
+	    0.0
+	};
+	let fx = if dx.abs() < s {
+	    parabolic(score(dy,dx - 1),f0,score(dy,dx + 1))
+	} else {
+	    0.0
+	};
+	let offset = (dy as Real + fy,dx as Real + fx);
+
+	let mut centroids = Vec::new();
+	for &(x,y) in anchors.iter() {
+	    let (iyf,ixf) = grid.index(x,y);
+	    if let Some((cy,cx)) = registration::centroid(layers,mask,iyf,ixf,r) {
+		centroids.push((cy - iyf,cx - ixf));
+	    }
+	}
+	let gerber_offset = if centroids.is_empty() {
+	    None
+	} else {
+	    let m = centroids.len() as Real;
+	    Some((centroids.iter().map(|c| c.0).sum::<Real>() / m,
+		  centroids.iter().map(|c| c.1).sum::<Real>() / m))
+	};
+
+	let d = hypot(offset.0,offset.1) * grid.delta;
+	if d > params.tolerance {
+	    warn!("Layer {} is offset by {:.4} mm relative to the first layer",
+		  ilay,d);
+	}
+	result.push(LayerAlignment {
+	    offset,
+	    correlation:f0 / auto as Real,
+	    gerber_offset
+	});
+    }
+    Ok(result)
+}
+
+/// Overlay of the first layer (red) and the given layer (green),
+/// with common anchors in blue
+pub fn overlay(layers:&Array2<u16>,ilay:usize,grid:&Grid,
+	       anchors:&[(Real,Real)])->Array3<u8> {
+    let (ny,nx) = layers.dim();
+    let mask = 1 << ilay;
+    let mut img = Array3::zeros((ny,nx,3));
+    for iy in 0..ny {
+	for ix in 0..nx {
+	    let l = layers[[iy,ix]];
+	    if l & 1 != 0 {
+		img[[iy,ix,0]] = 255;
+	    }
+	    if l & mask != 0 {
+		img[[iy,ix,1]] = 255;
+	    }
+	}
+    }
+    for &(x,y) in anchors.iter() {
+	if let Some((iy,ix)) = grid.pixel(x,y) {
+	    img[[iy,ix,2]] = 255;
+	}
+    }
+    img
+}
+
+#[cfg(test)]
+#[test]
+fn test_parabolic() {
+    let f = |x:Real| 5.0 - sq(x - 0.3);
+    assert!((parabolic(f(-1.0),f(0.0),f(1.0)) - 0.3).abs() < 1e-12);
+    assert_eq!(parabolic(1.0,1.0,1.0),0.0);
+    assert_eq!(parabolic(0.0,1.0,1.5),0.5);
+}
+
+#[cfg(test)]
+#[test]
+fn test_check() {
+    use crate::gerber::Point;
+
+    // Square pads of 7 by 7 pixels on both layers, those of the second
+    // layer shifted down by one pixel and left by two
+    let grid = Grid::new(100,100,&config::Point { x:1.0,y:2.0 },254.0);
+    let mut layers = Array2::zeros((100,100));
+    let mut points = Vec::new();
+    for (iy,ix) in [(20,20),(20,70),(50,40),(80,25),(75,75)] {
+	for (mask,dy,dx) in [(1,0,0),(2,1,-2)] {
+	    for jy in iy - 3..=iy + 3 {
+		for jx in ix - 3..=ix + 3 {
+		    layers[[(jy as isize + dy) as usize,(jx as isize + dx) as usize]] |= mask;
+		}
+	    }
+	}
+	let (x,y) = grid.point(iy,ix);
+	points.push(Point { x,y });
+    }
+    let infos = || NetInfos {
+	index:[("A".to_string(),points.clone())].into_iter().collect(),
+	ranks:Default::default()
+    };
+    let als = check(&layers,2,&grid,&[infos(),infos()],&config::Alignment::default()).unwrap();
+    assert_eq!(als[0].offset,(0.0,0.0));
+    assert_eq!(als[1].offset,(1.0,-2.0));
+    assert_eq!(als[1].correlation,1.0);
+    let (gy,gx) = als[1].gerber_offset.unwrap();
+    assert!((gy - 1.0).abs() < 1e-9 && (gx + 2.0).abs() < 1e-9);
+}
@@ -0,0 +1,480 @@
+use log::info;
+use std::{
+    collections::{BTreeMap,BTreeSet,VecDeque},
+    fs::File,
+    io::{Read,Seek,SeekFrom}
+};
+
+use crate::{
+    common::*,
+    math::*,
+    config::{self,Config,Connectivity,Bands},
+    binarize,
+    disk,
+    drill,
+    matching::{self,PointMatch},
+    islands::{self,Extent},
+    components::{self,ConnectedComponents,Overlap,UnionFind},
+    gerber::NetInfos,
+    grid::Grid,
+    Extraction,
+    LAYERS_MAX
+};
+
+/// Row-by-row reader of a PNG layer bitmap, converting pixels to
+/// binarization levels
+struct LayerReader {
+    reader:png::Reader<File>,
+    color:png::ColorType,
+    binarization:config::Binarization,
+    histogram:[usize;256]
+}
+
+impl LayerReader {
+    fn open(path:&str,b:&config::Binarization)->Res<Self> {
+	let mut fd = File::open(path)?;
+	let mut magic = [0;4];
+	let n = fd.read(&mut magic)?;
+	if n == 4 && (magic == *b"II*\0" || magic == *b"MM\0*") {
+	    return Err(error(&format!(
+		"{}: TIFF files cannot be read in bands, convert them to PNG \
+		 or disable bands",path)));
+	}
+	fd.seek(SeekFrom::Start(0))?;
+	let mut decoder = png::Decoder::new(fd);
+	decoder.set_transformations(png::Transformations::EXPAND |
+				    png::Transformations::STRIP_16);
+	let (_,mut reader) = decoder.read_info()?;
+	if reader.info().interlaced {
+	    return Err(error(&format!(
+		"{}: interlaced PNG files cannot be read in bands",path)));
+	}
+	let (color,_) = reader.output_color_type();
+	Ok(Self {
+	    reader,
+	    color,
+	    binarization:b.clone(),
+	    histogram:[0;256]
+	})
+    }
+
+    fn dim(&self)->(usize,usize) {
+	let info = self.reader.info();
+	(info.height as usize,info.width as usize)
+    }
+
+    /// Reads the levels of the next row, starting at column ix0
+    fn next_row(&mut self,ix0:usize,out:&mut [u8])->Res<()> {
+	let row = self.reader.next_row()?
+	    .ok_or_else(|| error("Premature end of PNG file"))?;
+	for (k,v) in out.iter_mut().enumerate() {
+	    let ix = ix0 + k;
+	    let p = match self.color {
+		png::ColorType::Grayscale => {
+		    let g = row[ix];
+		    [g,g,g,255]
+		},
+		png::ColorType::GrayscaleAlpha => {
+		    let g = row[2*ix];
+		    [g,g,g,row[2*ix + 1]]
+		},
+		png::ColorType::RGB | png::ColorType::Indexed =>
+		    [row[3*ix],row[3*ix + 1],row[3*ix + 2],255],
+		png::ColorType::RGBA =>
+		    [row[4*ix],row[4*ix + 1],row[4*ix + 2],row[4*ix + 3]]
+	    };
+	    *v = binarize::level(p,&self.binarization);
+	    self.histogram[*v as usize] += 1;
+	}
+	Ok(())
+    }
+
+    fn skip_row(&mut self)->Res<()> {
+	self.reader.next_row()?;
+	Ok(())
+    }
+}
+
+/// Streams the layer bitmaps in bands of full-width rows whose size
+/// is set by the memory budget, labelling each band and merging
+/// components across band boundaries.  Overlaps are accumulated per
+/// band.  Searches for copper near flash points are deferred until
+/// the rows they cover have been read, and can reach back into
+/// previous bands, whose last rows are kept.  Only PNG bitmaps can
+/// be streamed; see Bands for what the budget leaves out.
+pub fn extract(config:&Config,bands:&Bands,net_infos:&[NetInfos])
+	       ->Res<Extraction> {
+    let nlay = config.layers.len();
+    if nlay > LAYERS_MAX {
+	return Err(error(&format!(
+	    "Too many layers: {}, at most {} are supported",
+	    nlay,LAYERS_MAX)));
+    }
+    if config.layers.iter().any(|l| l.etch_offset != 0.0) {
+	return Err(error("Etch offsets are not supported with bands"));
+    }
+    if config.coplanar.is_some() {
+	return Err(error("Coplanar coupling is not supported with bands"));
+    }
+    if config.field.is_some() {
+	return Err(error("The field solver is not supported with bands"));
+    }
+    if config.random_walk.is_some() {
+	return Err(error("Random walk extraction is not supported with bands"));
+    }
+    if config.cross_section.is_some() {
+	return Err(error("Cross-sections are not supported with bands"));
+    }
+    if config.layers.iter().any(|l| l.has_own_geometry()) {
+	return Err(error("Per-layer origin and dpi are not supported \
+			  with bands"));
+    }
+    let mut readers = Vec::new();
+    for lay in config.layers.iter() {
+	let path = format!("{}/{}",config.input,lay.bitmap);
+	readers.push(LayerReader::open(&path,&lay.binarization)?);
+    }
+    let (ny,nx) = readers[0].dim();
+    for r in readers.iter() {
+	let (nyp,nxp) = r.dim();
+	if ny != nyp || nx != nxp {
+	    return Err(error(&format!(
+		"Incoherent dimensions: ({},{}) vs ({},{})",
+		ny,nx,nyp,nxp)));
+	}
+    }
+    components::check_dim(ny,nx)?;
+
+    let full = Grid::new(ny,nx,&config.origin,config.dpi);
+    let window = match &config.roi {
+	Some(roi) => Some(full.window(roi)?),
+	None => None
+    };
+    let grid = match &window {
+	Some(w) => full.sub(w),
+	None => full.clone()
+    };
+    let (iy0,ix0) = window.as_ref().map(|w| (w.iy0,w.ix0)).unwrap_or((0,0));
+    let (nyw,nxw) = (grid.ny,grid.nx);
+    let conns : Vec<Connectivity> = config.layers.iter()
+	.map(|l| l.connectivity.unwrap_or(config.connectivity))
+	.collect();
+
+    // Layer mask, labels of every layer and coverage
+    let bytes_per_row = nxw * (3 + 5*nlay);
+    let rows = ((bands.memory * 1e6) as usize / bytes_per_row)
+	.max(1)
+	.min(nyw);
+    info!("Processing {} x {} pixels in bands of {} rows",nyw,nxw,rows);
+
+    let mut partial_nets : BTreeSet<String> = BTreeSet::new();
+    let mut matches : Vec<Vec<(String,Vec<PointMatch>)>> = Vec::new();
+    let mut by_row : Vec<BTreeMap<usize,Vec<(usize,usize)>>> = Vec::new();
+    for ni in net_infos.iter().take(nlay) {
+	let lm = matching::points(&grid,ni);
+	let mut br : BTreeMap<usize,Vec<(usize,usize)>> = BTreeMap::new();
+	for (inet,(_,pms)) in lm.iter().enumerate() {
+	    for (ipt,pm) in pms.iter().enumerate() {
+		if let Some((iy,_)) = pm.pixel {
+		    br.entry(iy).or_default().push((inet,ipt));
+		}
+	    }
+	}
+	matches.push(lm);
+	by_row.push(br);
+    }
+
+    for r in readers.iter_mut() {
+	for _ in 0..iy0 {
+	    r.skip_row()?;
+	}
+    }
+
+    let mut ufs : Vec<UnionFind> = (0..nlay).map(|_| UnionFind::new()).collect();
+    let mut prev_row : Vec<Vec<u32>> = vec![Vec::new();nlay];
+    // Last row of the previous band after drilling
+    let mut prev_drilled : Vec<Vec<u32>> = vec![Vec::new();nlay];
+    let pairs = config.layer_pairs();
+    let mut overlaps : BTreeMap<(usize,usize),BTreeMap<(u32,u32),Overlap>> =
+	pairs.iter().map(|&p| (p,BTreeMap::new())).collect();
+    let mut cut : Vec<BTreeSet<u32>> = vec![BTreeSet::new();nlay];
+    let holes = drill::load_all(config)?;
+    // Extent of each provisional component, by global label - 1
+    let mut band_extents : Vec<Vec<Extent>> = vec![Vec::new();nlay];
+    let mut levels = vec![0;nxw];
+    let radius = config.matching.radius / grid.delta;
+    // Flash points off copper waiting for the rows below them, and
+    // global labels of the last rows before the current band, which
+    // searches of such points can reach
+    let reach_rows = radius.ceil() as usize + 2;
+    let mut pending : Vec<Vec<(usize,usize)>> = vec![Vec::new();nlay];
+    let mut context : Vec<VecDeque<Vec<u32>>> = vec![VecDeque::new();nlay];
+    let mut iy = 0;
+    while iy < nyw {
+	let h = rows.min(nyw - iy);
+	let mut mask : Array2<u16> = Array2::zeros((h,nxw));
+	let mut coverage : Vec<Option<Array2<u8>>> = config.layers.iter()
+	    .map(|l| if l.binarization.coverage {
+		Some(Array2::zeros((h,nxw)))
+	    } else {
+		None
+	    })
+	    .collect();
+	for r in 0..h {
+	    for ilay in 0..nlay {
+		readers[ilay].next_row(ix0,&mut levels)?;
+		let threshold = config.layers[ilay].binarization.threshold;
+		for ix in 0..nxw {
+		    if levels[ix] > threshold {
+			mask[[r,ix]] |= 1 << ilay;
+			if let Some(c) = &mut coverage[ilay] {
+			    c[[r,ix]] = levels[ix];
+			}
+		    }
+		}
+	    }
+	}
+
+	let mut ccs = Vec::new();
+	let mut bases = Vec::new();
+	for ilay in 0..nlay {
+	    let mut cc = ConnectedComponents::from_array(&mask,1 << ilay,conns[ilay])?;
+	    let uf = &mut ufs[ilay];
+	    let base = uf.len() as u32;
+	    for st in cc.stats.iter() {
+		uf.make();
+		let mut e : Extent = st.into();
+		e.min.0 += iy;
+		e.max.0 += iy;
+		band_extents[ilay].push(e);
+	    }
+	    let g = |l:u32| if l == 0 { 0 } else { base + l - 1 };
+
+	    let reach : &[isize] = match conns[ilay] {
+		Connectivity::Four => &[0],
+		Connectivity::Eight => &[-1,0,1]
+	    };
+	    for (ix,&a) in prev_row[ilay].iter().enumerate() {
+		if a == 0 {
+		    continue;
+		}
+		for &d in reach {
+		    let jx = ix as isize + d;
+		    if 0 <= jx && jx < nxw as isize {
+			let l = cc.labels[[0,jx as usize]];
+			if l > 0 {
+			    uf.union(a,g(l));
+			}
+		    }
+		}
+	    }
+
+	    if let Some(w) = &window {
+		for ((r,ix),&l) in cc.labels.indexed_iter() {
+		    if l > 0 && w.on_cut_edge(iy + r,ix) {
+			cut[ilay].insert(g(l));
+		    }
+		}
+	    }
+
+	    for r in 0..h {
+		if let Some(pts) = by_row[ilay].get(&(iy + r)) {
+		    for &(inet,ipt) in pts.iter() {
+			let pm = &mut matches[ilay][inet].1[ipt];
+			let (_,ix) = pm.pixel.unwrap();
+			pm.label = g(cc.labels[[r,ix]]);
+			if pm.label == 0 && radius > 0.0 {
+			    pending[ilay].push((inet,ipt));
+			}
+		    }
+		}
+	    }
+
+	    if radius > 0.0 {
+		let ctx = &mut context[ilay];
+		let top = iy - ctx.len();
+		let label = |jy:usize,jx:usize| if jy < ctx.len() {
+		    ctx[jy][jx]
+		} else {
+		    g(cc.labels[[jy - ctx.len(),jx]])
+		};
+		pending[ilay].retain(|&(inet,ipt)| {
+		    let pm = &mut matches[ilay][inet].1[ipt];
+		    let (iyf,ixf) = grid.index(pm.x,pm.y);
+		    if iy + h < nyw && iyf + radius + 2.0 > (iy + h) as Real {
+			return true;
+		    }
+		    if let Some((jy,jx,d)) =
+			disk::nearest(iyf - top as Real,ixf,radius,ctx.len() + h,nxw,
+				      |jy,jx| label(jy,jx) > 0) {
+			pm.label = label(jy,jx);
+			pm.distance = Some(d * grid.delta);
+		    }
+		    false
+		});
+		for r in 0..h {
+		    ctx.push_back((0..nxw).map(|ix| g(cc.labels[[r,ix]])).collect());
+		    if ctx.len() > 2*reach_rows {
+			ctx.pop_front();
+		    }
+		}
+	    }
+
+	    prev_row[ilay] = (0..nxw).map(|ix| g(cc.labels[[h - 1,ix]])).collect();
+	    drill::clear(&mut cc.labels,&grid,&holes,iy);
+	    bases.push(base);
+	    ccs.push(cc);
+	}
+
+	for &(ilay,jlay) in pairs.iter() {
+	    let shield = components::shield(&ccs,ilay,jlay);
+	    let ov_pair = overlaps.get_mut(&(ilay,jlay)).unwrap();
+	    for ((a,b),ov) in components::overlaps(&ccs[ilay],&ccs[jlay],
+						   coverage[ilay].as_ref(),
+						   coverage[jlay].as_ref(),
+						   shield.as_ref()) {
+		let key = (bases[ilay] + a as u32,bases[jlay] + b as u32);
+		ov_pair.entry(key).or_default().merge(&ov);
+	    }
+
+	    // Boundary of the overlaps between this band and the
+	    // previous one
+	    if iy > 0 {
+		let g = |klay:usize,l:u32| if l == 0 { 0 } else { bases[klay] + l - 1 };
+		for ix in 0..nxw {
+		    let above = (prev_drilled[ilay][ix],prev_drilled[jlay][ix]);
+		    let below = (g(ilay,ccs[ilay].labels[[0,ix]]),
+				 g(jlay,ccs[jlay].labels[[0,ix]]));
+		    let inside_above = above.0 > 0 && above.1 > 0 &&
+			(ilay + 1..jlay).all(|k| prev_drilled[k][ix] == 0);
+		    let inside_below = below.0 > 0 && below.1 > 0 &&
+			!shield.as_ref().map(|s| s[[0,ix]]).unwrap_or(false);
+		    if inside_above != inside_below {
+			let key = if inside_above { above } else { below };
+			ov_pair.entry(key).or_default().perimeter += 1;
+		    }
+		}
+	    }
+	}
+	for ilay in 0..nlay {
+	    let g = |l:u32| if l == 0 { 0 } else { bases[ilay] + l - 1 };
+	    prev_drilled[ilay] = (0..nxw).map(|ix| g(ccs[ilay].labels[[h - 1,ix]])).collect();
+	}
+
+	iy += h;
+    }
+
+    // Number the merged components in order of first appearance
+    let mut finals : Vec<Vec<u32>> = Vec::new();
+    let mut names : Vec<Vec<Option<String>>> = Vec::new();
+    let mut n_shorts = 0;
+    let mut hits_per_layer = Vec::new();
+    for (ilay,uf) in ufs.iter_mut().enumerate() {
+	let mut of_root = vec![0;uf.len()];
+	let mut fin = vec![0;uf.len()];
+	let mut m = 0;
+	for l in 1..uf.len() as u32 {
+	    let r = uf.find(l) as usize;
+	    if of_root[r] == 0 {
+		m += 1;
+		of_root[r] = m;
+	    }
+	    fin[l as usize] = of_root[r];
+	}
+	info!("Layer {} ({}), number of components: {}",
+	      ilay,config.layers[ilay].name,m);
+	finals.push(fin);
+    }
+
+    for ilay in 0..nlay {
+	let lname = &config.layers[ilay].name;
+	binarize::check(lname,&readers[ilay].histogram,
+			config.layers[ilay].binarization.threshold);
+
+	let m = finals[ilay].iter().copied().max().unwrap_or(0) as usize;
+	let (mut component_names,n,hits) = matching::name_components(
+	    config,ilay,&grid,m,&matches[ilay],|l| finals[ilay][l as usize] as usize,
+	    window.as_ref().map(|_| &mut partial_nets))?;
+	n_shorts += n;
+	hits_per_layer.push(hits);
+
+	let mut extents : Vec<Option<Extent>> = vec![None;m];
+	for (l,e) in band_extents[ilay].iter().enumerate() {
+	    let icom = finals[ilay][l + 1] as usize - 1;
+	    match &mut extents[icom] {
+		Some(f) => f.merge(e),
+		None => extents[icom] = Some(e.clone())
+	    }
+	}
+	let extents : Vec<Extent> = extents.into_iter().flatten().collect();
+	islands::unnamed(config,ilay,&mut component_names,&extents,&grid,None)?;
+	names.push(component_names);
+
+	for &l in cut[ilay].iter() {
+	    let icom = finals[ilay][l as usize] as usize;
+	    if let Some(name) = &names[ilay][icom - 1] {
+		partial_nets.insert(name.clone());
+	    }
+	}
+    }
+
+    matching::check(config,&hits_per_layer,&holes,n_shorts)?;
+
+    let overlaps = overlaps.iter()
+	.map(|(&(ilay,jlay),ov)| {
+	    let mut res : BTreeMap<(usize,usize),Overlap> = BTreeMap::new();
+	    for (&(a,b),o) in ov.iter() {
+		let icomi = finals[ilay][a as usize] as usize - 1;
+		let icomj = finals[jlay][b as usize] as usize - 1;
+		res.entry((icomi,icomj)).or_default().merge(o);
+	    }
+	    ((ilay,jlay),res)
+	})
+	.collect();
+
+    Ok(Extraction {
+	grid,
+	window,
+	names,
+	overlaps,
+	coplanar:vec![BTreeMap::new();nlay],
+	labels:Vec::new(),
+	partial_nets
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test_band_overlaps() {
+    // A 5 x 6 rectangle over a plate, read in bands of 2 rows so that
+    // band boundaries cross the overlap
+    let dir = std::env::temp_dir().join(format!("capest-bands-{}",std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let dir = dir.to_str().unwrap();
+    let (ny,nx) = (10,12);
+    let write = |name:&str,f:&dyn Fn(usize,usize)->bool| {
+	let data : Vec<u8> = (0..ny*nx).map(|k| if f(k / nx,k % nx) { 255 } else { 0 }).collect();
+	let fd = File::create(format!("{}/{}",dir,name)).unwrap();
+	let mut enc = png::Encoder::new(std::io::BufWriter::new(fd),nx as u32,ny as u32);
+	enc.set_color(png::ColorType::Grayscale);
+	enc.set_depth(png::BitDepth::Eight);
+	enc.write_header().unwrap().write_image_data(&data).unwrap();
+    };
+    write("rect.png",&|iy,ix| (2..7).contains(&iy) && (3..9).contains(&ix));
+    write("plate.png",&|iy,ix| (1..9).contains(&iy) && (1..11).contains(&ix));
+    let config : Config = ron::from_str(&format!(
+	"(input:\"{0}\",output:\"{0}\",\
+	  layers:[(name:\"A\",bitmap:\"rect.png\",gerber:\"\"),\
+		  (name:\"B\",bitmap:\"plate.png\",gerber:\"\")],\
+	  roi:None,mark:None,origin:(x:0.0,y:0.0),dpi:254.0,\
+	  eps_rel:4.0,thickness:0.1,cap_min:0.0)",dir)).unwrap();
+    let bands = Bands { memory:2.0 * nx as Real * (3.0 + 5.0 * 2.0) * 1e-6 };
+    let no_nets = || NetInfos { index:BTreeMap::new(),ranks:BTreeMap::new() };
+    let ex = extract(&config,&bands,&[no_nets(),no_nets()]).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    let ov = &ex.overlaps[&(0,1)];
+    assert_eq!(ov.len(),1);
+    let ov = ov.values().next().unwrap();
+    assert_eq!(ov.area,30.0);
+    assert_eq!(ov.perimeter,2*(5 + 6));
+}
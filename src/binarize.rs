@@ -0,0 +1,153 @@
+use log::{info,warn};
+
+use crate::{
+    math::*,
+    config::{Binarization,Channel,AlphaMode}
+};
+
+/// Fraction of copper pixels that may fall between the configured
+/// threshold and Otsu's threshold before a warning is issued
+const DISPUTED_MAX : Real = 0.01;
+
+/// Level of an RGBA pixel compared against the binarization
+/// threshold
+pub fn level(p:[u8;4],b:&Binarization)->u8 {
+    let mut v = match b.channel {
+	Channel::Luma =>
+	    ((2126 * p[0] as u32 +
+	      7152 * p[1] as u32 +
+	      722 * p[2] as u32) / 10000) as u8,
+	Channel::Red => p[0],
+	Channel::Green => p[1],
+	Channel::Blue => p[2],
+	Channel::Alpha => p[3]
+    };
+    if b.invert {
+	v = 255 - v;
+    }
+    match b.alpha {
+	AlphaMode::Ignore => (),
+	AlphaMode::Mask => if p[3] < 128 { v = 0 },
+	AlphaMode::Premultiply =>
+	    v = ((v as u32 * p[3] as u32 + 127) / 255) as u8
+    }
+    v
+}
+
+/// Converts an RGBA image into levels
+pub fn levels(img:&Array3<u8>,b:&Binarization)->Array2<u8> {
+    let (ny,nx,_) = img.dim();
+    let mut a = Array2::zeros((ny,nx));
+    for iy in 0..ny {
+	for ix in 0..nx {
+	    let p = [img[[iy,ix,0]],img[[iy,ix,1]],img[[iy,ix,2]],img[[iy,ix,3]]];
+	    a[[iy,ix]] = level(p,b);
+	}
+    }
+    a
+}
+
+pub fn histogram(a:&Array2<u8>)->[usize;256] {
+    let mut h = [0;256];
+    for &v in a.iter() {
+	h[v as usize] += 1;
+    }
+    h
+}
+
+/// Threshold maximizing the between-class variance; values above
+/// it form the foreground
+pub fn otsu(h:&[usize;256])->u8 {
+    let total : usize = h.iter().sum();
+    let sum : Real = h.iter().enumerate().map(|(i,&n)| (i * n) as Real).sum();
+    let mut w0 = 0;
+    let mut sum0 = 0.0;
+    let mut best = (0.0,0);
+    for (t,&n) in h.iter().enumerate().take(255) {
+	w0 += n;
+	sum0 += (t * n) as Real;
+	let w1 = total - w0;
+	if w0 == 0 || w1 == 0 {
+	    continue;
+	}
+	let m0 = sum0 / w0 as Real;
+	let m1 = (sum - sum0) / w1 as Real;
+	let s = w0 as Real * w1 as Real * sq(m0 - m1);
+	if s > best.0 {
+	    best = (s,t);
+	}
+    }
+    best.1 as u8
+}
+
+/// Otsu's threshold, and the fraction of the copper pixels at the
+/// given threshold that it classifies differently
+pub fn disputed(h:&[usize;256],threshold:u8)->(u8,Real) {
+    let copper : usize = h[threshold as usize + 1..].iter().sum();
+    let t_otsu = otsu(h);
+    let lo = threshold.min(t_otsu) as usize;
+    let hi = threshold.max(t_otsu) as usize;
+    let disputed : usize = h[lo + 1..=hi].iter().sum();
+    (t_otsu,disputed as Real / copper as Real)
+}
+
+/// Warns if the histogram suggests the threshold is inappropriate
+pub fn check(name:&str,h:&[usize;256],threshold:u8) {
+    let total : usize = h.iter().sum();
+    let copper : usize = h[threshold as usize + 1..].iter().sum();
+    info!("Layer {}: {:.2}% copper at threshold {}",
+	  name,
+	  100.0 * copper as Real / total as Real,
+	  threshold);
+    if copper == 0 {
+	warn!("Layer {}: no copper at threshold {}",name,threshold);
+	return;
+    }
+    if copper == total {
+	warn!("Layer {}: all pixels have copper at threshold {}; \
+	       check inversion and alpha handling",name,threshold);
+	return;
+    }
+    let (t_otsu,ratio) = disputed(h,threshold);
+    if ratio > DISPUTED_MAX {
+	warn!("Layer {}: threshold {} classifies {:.1}% of copper pixels \
+	       differently from Otsu's threshold {}",
+	      name,threshold,100.0 * ratio,t_otsu);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_levels() {
+    let b = |channel,invert,alpha| Binarization { channel,invert,alpha,..Binarization::default() };
+    let p = [200,100,50,128];
+    assert_eq!(level(p,&b(Channel::Luma,false,AlphaMode::Ignore)),117);
+    assert_eq!(level(p,&b(Channel::Green,true,AlphaMode::Ignore)),155);
+    assert_eq!(level(p,&b(Channel::Red,false,AlphaMode::Premultiply)),100);
+    assert_eq!(level([200,100,50,127],&b(Channel::Red,false,AlphaMode::Mask)),0);
+    assert_eq!(level(p,&b(Channel::Alpha,false,AlphaMode::Mask)),128);
+}
+
+#[cfg(test)]
+#[test]
+fn test_otsu() {
+    // Two levels with a little noise: Otsu's threshold separates them
+    // and agrees with any threshold in between
+    let mut h = [0;256];
+    for (v,n) in [(20,3000),(21,500),(19,500),(230,1000),(229,200),(231,200)] {
+	h[v] = n;
+    }
+    let t = otsu(&h);
+    assert!((21..229).contains(&t));
+    assert_eq!(disputed(&h,128),(t,0.0));
+
+    // A single broad peak: half of it is split off by Otsu's
+    // threshold and the configured one disagrees
+    let mut h = [0;256];
+    for (v,n) in h.iter_mut().enumerate() {
+	*n = 1000 - (v as isize - 128).unsigned_abs().min(1000);
+    }
+    let (t,ratio) = disputed(&h,200);
+    assert!((100..160).contains(&t));
+    assert!(ratio > DISPUTED_MAX);
+}
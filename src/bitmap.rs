@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    common::*,
+    math::*,
+    config::{Point,Resolution},
+    grid::Grid,
+    fax,
+    ndarray_image
+};
+
+const TAG_WIDTH : u16 = 256;
+const TAG_HEIGHT : u16 = 257;
+const TAG_BITS_PER_SAMPLE : u16 = 258;
+const TAG_COMPRESSION : u16 = 259;
+const TAG_PHOTOMETRIC : u16 = 262;
+const TAG_FILL_ORDER : u16 = 266;
+const TAG_STRIP_OFFSETS : u16 = 273;
+const TAG_SAMPLES_PER_PIXEL : u16 = 277;
+const TAG_ROWS_PER_STRIP : u16 = 278;
+const TAG_STRIP_BYTE_COUNTS : u16 = 279;
+const TAG_TILE_WIDTH : u16 = 322;
+
+const COMPRESSION_NONE : u32 = 1;
+const COMPRESSION_G4 : u32 = 4;
+const COMPRESSION_PACKBITS : u32 = 32773;
+
+/// Loads a layer bitmap as an RGBA array.  Bilevel TIFF files,
+/// including CCITT Group 4 compressed ones, are decoded here; other
+/// files are handed to the image crate, which also covers PBM and
+/// 16-bit images (reduced to 8 bits).
+pub fn open(path:&str)->Res<Array3<u8>> {
+    let data = std::fs::read(path)?;
+    if let Some(img) = bilevel_tiff(&data)
+	.map_err(|e| error(&format!("{}: {}",path,e)))? {
+	return Ok(img);
+    }
+    Ok(ndarray_image::open_image(path,ndarray_image::Colors::Rgba)?)
+}
+
+struct TiffReader<'a> {
+    data:&'a [u8],
+    big_endian:bool
+}
+
+impl<'a> TiffReader<'a> {
+    fn bytes(&self,offset:usize,n:usize)->Res<&'a [u8]> {
+	self.data.get(offset..offset + n)
+	    .ok_or_else(|| error("Truncated TIFF file"))
+    }
+
+    fn u16(&self,offset:usize)->Res<u32> {
+	let b = self.bytes(offset,2)?;
+	let b = [b[0],b[1]];
+	Ok(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) } as u32)
+    }
+
+    fn u32(&self,offset:usize)->Res<u32> {
+	let b = self.bytes(offset,4)?;
+	let b = [b[0],b[1],b[2],b[3]];
+	Ok(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    /// Integer values of the entries of the first image file directory
+    fn directory(&self)->Res<BTreeMap<u16,Vec<u32>>> {
+	let mut entries = BTreeMap::new();
+	let ifd = self.u32(4)? as usize;
+	let n = self.u16(ifd)? as usize;
+	for i in 0..n {
+	    let e = ifd + 2 + 12*i;
+	    let tag = self.u16(e)? as u16;
+	    let typ = self.u16(e + 2)?;
+	    let count = self.u32(e + 4)? as usize;
+	    let size = match typ {
+		1 => 1,
+		3 => 2,
+		4 => 4,
+		_ => continue
+	    };
+	    let mut offset = e + 8;
+	    if count * size > 4 {
+		offset = self.u32(offset)? as usize;
+	    }
+	    let mut values = Vec::with_capacity(count);
+	    for k in 0..count {
+		let o = offset + k*size;
+		values.push(match size {
+		    1 => self.bytes(o,1)?[0] as u32,
+		    2 => self.u16(o)?,
+		    _ => self.u32(o)?
+		});
+	    }
+	    entries.insert(tag,values);
+	}
+	Ok(entries)
+    }
+}
+
+fn unpack_bits(src:&[u8],width:usize,row:&mut [u8])->Res<()> {
+    if src.len() < width.div_ceil(8) {
+	return Err(error("Truncated TIFF strip"));
+    }
+    for (ix,v) in row.iter_mut().enumerate() {
+	*v = (src[ix >> 3] >> (7 - (ix & 7))) & 1;
+    }
+    Ok(())
+}
+
+fn unpack_packbits(src:&[u8])->Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < src.len() {
+	let n = src[i] as i8;
+	i += 1;
+	if n >= 0 {
+	    let m = (n as usize + 1).min(src.len() - i);
+	    out.extend_from_slice(&src[i..i + m]);
+	    i += m;
+	} else if n != -128 && i < src.len() {
+	    out.extend(std::iter::repeat_n(src[i],(1 - n as isize) as usize));
+	    i += 1;
+	}
+    }
+    out
+}
+
+/// Decodes a single-channel, one bit per sample TIFF file.  Returns
+/// None if the data is not such a file.
+fn bilevel_tiff(data:&[u8])->Res<Option<Array3<u8>>> {
+    let big_endian = match data.get(0..4) {
+	Some(b"II*\0") => false,
+	Some(b"MM\0*") => true,
+	_ => return Ok(None)
+    };
+    let tr = TiffReader { data,big_endian };
+    let dir = tr.directory()?;
+    let get = |tag:u16,default:Option<u32>|->Res<u32> {
+	dir.get(&tag)
+	    .and_then(|v| v.first().copied())
+	    .or(default)
+	    .ok_or_else(|| error(&format!("Missing TIFF tag {}",tag)))
+    };
+    if get(TAG_BITS_PER_SAMPLE,Some(1))? != 1 ||
+	get(TAG_SAMPLES_PER_PIXEL,Some(1))? != 1 {
+	return Ok(None);
+    }
+    if dir.contains_key(&TAG_TILE_WIDTH) {
+	return Err(error("Tiled bilevel TIFF files are not supported"));
+    }
+    let nx = get(TAG_WIDTH,None)? as usize;
+    let ny = get(TAG_HEIGHT,None)? as usize;
+    let compression = get(TAG_COMPRESSION,Some(COMPRESSION_NONE))?;
+    let white_is_zero = get(TAG_PHOTOMETRIC,Some(0))? == 0;
+    let reverse = get(TAG_FILL_ORDER,Some(1))? == 2;
+    let rows_per_strip = (get(TAG_ROWS_PER_STRIP,Some(ny as u32))? as usize).min(ny);
+    let offsets = dir.get(&TAG_STRIP_OFFSETS)
+	.ok_or_else(|| error("Missing TIFF strip offsets"))?;
+    let counts = dir.get(&TAG_STRIP_BYTE_COUNTS)
+	.ok_or_else(|| error("Missing TIFF strip byte counts"))?;
+    if offsets.len() != counts.len() ||
+	offsets.len() * rows_per_strip.max(1) < ny {
+	return Err(error("Inconsistent TIFF strips"));
+    }
+
+    let mut img = Array3::zeros((ny,nx,4));
+    let mut row = vec![0;nx];
+    let stride = nx.div_ceil(8);
+    for (istrip,(&offset,&count)) in offsets.iter().zip(counts.iter()).enumerate() {
+	let iy0 = istrip * rows_per_strip;
+	if iy0 >= ny {
+	    break;
+	}
+	let n = rows_per_strip.min(ny - iy0);
+	let mut strip = tr.bytes(offset as usize,count as usize)?.to_vec();
+	if reverse && compression != COMPRESSION_G4 {
+	    for b in strip.iter_mut() {
+		*b = b.reverse_bits();
+	    }
+	}
+	let rows = match compression {
+	    COMPRESSION_G4 => fax::decode_g4(&strip,nx,n,reverse)?,
+	    COMPRESSION_NONE | COMPRESSION_PACKBITS => {
+		if compression == COMPRESSION_PACKBITS {
+		    strip = unpack_packbits(&strip);
+		}
+		let mut rows = Vec::with_capacity(n);
+		for k in 0..n {
+		    let src = strip.get(k*stride..).unwrap_or(&[]);
+		    unpack_bits(src,nx,&mut row)?;
+		    rows.push(row.clone());
+		}
+		rows
+	    },
+	    _ => return Err(error(&format!(
+		"Unsupported compression {} for bilevel TIFF",compression)))
+	};
+	for (k,r) in rows.iter().enumerate() {
+	    for (ix,&b) in r.iter().enumerate() {
+		let v = if (b == 1) == white_is_zero { 0 } else { 255 };
+		for c in 0..3 {
+		    img[[iy0 + k,ix,c]] = v;
+		}
+		img[[iy0 + k,ix,3]] = 255;
+	    }
+	}
+    }
+    Ok(Some(img))
+}
+
+/// Resamples a coverage map of the given origin and resolution onto
+/// a grid by bilinear interpolation; the outside of the map has no
+/// copper.
+pub fn resample(copper:&Array2<u8>,origin:&Point,dpi:&Resolution,grid:&Grid)
+		->Array2<u8> {
+    let (nys,nxs) = copper.dim();
+    let dx = 25.4 / dpi.x;
+    let dy = 25.4 / dpi.y;
+    let at = |iy:isize,ix:isize| {
+	if 0 <= iy && iy < nys as isize && 0 <= ix && ix < nxs as isize {
+	    copper[[iy as usize,ix as usize]] as Real
+	} else {
+	    0.0
+	}
+    };
+    Array2::from_shape_fn((grid.ny,grid.nx),|(iy,ix)| {
+	let (x,y) = grid.point(iy,ix);
+	let u = (x - origin.x)/dx - 0.5;
+	let v = nys as Real - (y - origin.y)/dy - 0.5;
+	let (iu,iv) = (u.floor(),v.floor());
+	let (fu,fv) = (u - iu,v - iv);
+	let (iu,iv) = (iu as isize,iv as isize);
+	let c =
+	    (1.0 - fu) * (1.0 - fv) * at(iv,iu) +
+	    fu * (1.0 - fv) * at(iv,iu + 1) +
+	    (1.0 - fu) * fv * at(iv + 1,iu) +
+	    fu * fv * at(iv + 1,iu + 1);
+	(c + 0.5).floor().min(255.0) as u8
+    })
+}
+
+/// Size of a grid of the given resolution covering a bitmap
+pub fn covering_dim(dim:(usize,usize),origin:&Point,dpi:&Resolution,
+		    grid_origin:&Point,delta:Real)->(usize,usize) {
+    let (ny,nx) = dim;
+    let x1 = origin.x + nx as Real * 25.4 / dpi.x;
+    let y1 = origin.y + ny as Real * 25.4 / dpi.y;
+    let n = |u:Real| (u / delta).round().max(1.0) as usize;
+    (n(y1 - grid_origin.y),n(x1 - grid_origin.x))
+}
+
+#[cfg(test)]
+#[test]
+fn test_packbits() {
+    // Literal run of 3, repeat run of 4, no-op, literal run of 1
+    let src = [2,10,11,12,0xfd,7,0x80,0,9];
+    assert_eq!(unpack_packbits(&src),vec![10,11,12,7,7,7,7,9]);
+    // Longest repeat run
+    assert_eq!(unpack_packbits(&[0x81,5]),vec![5;128]);
+    // Truncated literal run
+    assert_eq!(unpack_packbits(&[3,1,2]),vec![1,2]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_bilevel_tiff() {
+    // Little-endian TIFF with a single strip after the directory
+    let tiff = |compression:u32,strip:&[u8]| {
+	let tags : [(u16,u32);7] = [
+	    (TAG_WIDTH,8),(TAG_HEIGHT,2),(TAG_BITS_PER_SAMPLE,1),
+	    (TAG_COMPRESSION,compression),(TAG_PHOTOMETRIC,0),
+	    (TAG_STRIP_OFFSETS,8 + 2 + 12*7 + 4),
+	    (TAG_STRIP_BYTE_COUNTS,strip.len() as u32)
+	];
+	let mut data = b"II*\0".to_vec();
+	data.extend(8_u32.to_le_bytes());
+	data.extend((tags.len() as u16).to_le_bytes());
+	for (tag,value) in tags {
+	    data.extend(tag.to_le_bytes());
+	    data.extend(4_u16.to_le_bytes());
+	    data.extend(1_u32.to_le_bytes());
+	    data.extend(value.to_le_bytes());
+	}
+	data.extend(0_u32.to_le_bytes());
+	data.extend_from_slice(strip);
+	bilevel_tiff(&data).unwrap().unwrap()
+    };
+    // Two rows of 2 white, 4 black and 2 white pixels: raw, as a
+    // PackBits repeat run and G4 coded (horizontal mode, then three
+    // vertical modes)
+    let raw = tiff(COMPRESSION_NONE,&[0x3c,0x3c]);
+    let packbits = tiff(COMPRESSION_PACKBITS,&[0xff,0x3c]);
+    let g4 = tiff(COMPRESSION_G4,&[0x2e,0xfc]);
+    for ix in 0..8 {
+	let v = if (2..6).contains(&ix) { 0 } else { 255 };
+	for iy in 0..2 {
+	    assert_eq!(raw[[iy,ix,0]],v);
+	    assert_eq!(raw[[iy,ix,3]],255);
+	}
+    }
+    assert_eq!(packbits,raw);
+    assert_eq!(g4,raw);
+}
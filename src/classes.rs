@@ -0,0 +1,97 @@
+use regex::Regex;
+
+use crate::{
+    common::*,
+    config::NetClass
+};
+
+/// Net classes of the configuration with their compiled patterns
+pub struct NetClasses<'a> {
+    classes:Vec<(&'a NetClass,Vec<Regex>)>
+}
+
+impl<'a> NetClasses<'a> {
+    pub fn new(classes:&'a [NetClass])->Res<Self> {
+	let mut res = Vec::new();
+	for c in classes.iter() {
+	    let mut rexs = Vec::new();
+	    for p in c.patterns.iter() {
+		rexs.push(Regex::new(&format!("^(?:{})$",p))?);
+	    }
+	    res.push((c,rexs));
+	}
+	Ok(Self { classes:res })
+    }
+
+    pub fn is_empty(&self)->bool {
+	self.classes.is_empty()
+    }
+
+    /// First class having a pattern matching the net
+    pub fn class(&self,net:&str)->Option<&'a NetClass> {
+	self.classes.iter()
+	    .find(|(_,rexs)| rexs.iter().any(|r| r.is_match(net)))
+	    .map(|&(c,_)| c)
+    }
+
+    /// Name of the conductor a net belongs to, which is the class
+    /// name for aggregated classes, or None if the net is ignored
+    pub fn conductor(&self,net:&str)->Option<String> {
+	match self.class(net) {
+	    Some(c) if c.ignore => None,
+	    Some(c) if c.aggregate => Some(c.name.clone()),
+	    _ => Some(net.to_string())
+	}
+    }
+
+    /// Class of a conductor
+    pub fn conductor_class(&self,conductor:&str)->Option<&'a NetClass> {
+	self.classes.iter()
+	    .find(|(c,_)| c.aggregate && c.name == conductor)
+	    .map(|&(c,_)| c)
+	    .or_else(|| self.class(conductor))
+    }
+
+    pub fn is_reference(&self,conductor:&str)->bool {
+	self.conductor_class(conductor).map(|c| c.reference).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_classes() {
+    let class = |name:&str,patterns:&[&str],ignore,reference,aggregate| NetClass {
+	name:name.to_string(),
+	patterns:patterns.iter().map(|p| p.to_string()).collect(),
+	ignore,
+	reference,
+	aggregate
+    };
+    let config = [
+	class("Ground",&["GND","AGND"],false,true,true),
+	class("Power",&["VCC|V[0-9]+V[0-9]*"],false,true,false),
+	class("Unused",&["N/C","NC_.*"],true,false,false),
+	class("All",&[".*"],false,false,false)
+    ];
+    let classes = NetClasses::new(&config).unwrap();
+    assert!(!classes.is_empty());
+
+    // Patterns match whole names, and the first matching class wins
+    assert_eq!(classes.class("AGND").unwrap().name,"Ground");
+    assert_eq!(classes.class("GND2").unwrap().name,"All");
+    assert_eq!(classes.class("V3V3").unwrap().name,"Power");
+    assert_eq!(classes.class("NC_12").unwrap().name,"Unused");
+
+    // Aggregated nets become the class, ignored ones disappear
+    assert_eq!(classes.conductor("GND").as_deref(),Some("Ground"));
+    assert_eq!(classes.conductor("AGND").as_deref(),Some("Ground"));
+    assert_eq!(classes.conductor("VCC").as_deref(),Some("VCC"));
+    assert_eq!(classes.conductor("N/C"),None);
+    assert_eq!(classes.conductor("CLK").as_deref(),Some("CLK"));
+
+    assert!(classes.is_reference("Ground"));
+    assert!(classes.is_reference("V1V8"));
+    assert!(!classes.is_reference("CLK"));
+    assert!(!classes.is_reference("GND2"));
+    assert!(NetClasses::new(&[class("Bad",&["("],false,false,false)]).is_err());
+}
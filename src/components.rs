@@ -0,0 +1,363 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    common::*,
+    math::*,
+    config::Connectivity
+};
+
+/// Largest supported bitmap width or height
+pub const DIM_MAX : usize = i32::MAX as usize;
+
+/// Largest supported number of pixels per layer, as labels are 32 bits
+pub const PIXELS_MAX : usize = u32::MAX as usize;
+
+#[derive(Copy,Clone,Debug,PartialEq,PartialOrd,Ord,Eq)]
+pub struct CellId {
+    pub iy:i32,
+    pub ix:i32
+}
+
+impl CellId {
+    pub fn neighbours(&self)->[Self;4] {
+	let &Self { iy,ix } = self;
+	[
+	    Self { iy:iy - 1, ix },
+	    Self { iy:iy + 1, ix },
+	    Self { iy:iy, ix:ix - 1 },
+	    Self { iy:iy, ix:ix + 1 }
+	]
+    }
+}
+
+impl TryFrom<(usize,usize)> for CellId {
+    type Error = Box<dyn Error>;
+    fn try_from((iy,ix):(usize,usize))->Res<Self> {
+	check_dim(iy + 1,ix + 1)?;
+	Ok(Self { iy:iy as i32,ix:ix as i32 })
+    }
+}
+
+/// Checks that a bitmap of the given dimensions can be indexed by
+/// cell identifiers and labelled
+pub fn check_dim(ny:usize,nx:usize)->Res<()> {
+    if ny > DIM_MAX || nx > DIM_MAX {
+	return Err(error(&format!(
+	    "Bitmap dimensions {} x {} exceed the maximum of {}",
+	    ny,nx,DIM_MAX)));
+    }
+    if ny.checked_mul(nx).map(|n| n > PIXELS_MAX).unwrap_or(true) {
+	return Err(error(&format!(
+	    "Bitmap of {} x {} pixels exceeds the maximum of {} pixels",
+	    ny,nx,PIXELS_MAX)));
+    }
+    Ok(())
+}
+
+impl Into<(usize,usize)> for CellId {
+    fn into(self)->(usize,usize) {
+	(self.iy as usize,
+	 self.ix as usize)
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct ComponentStats {
+    /// Number of pixels
+    pub area:usize,
+    /// Bounding box, inclusive
+    pub min:CellId,
+    pub max:CellId,
+    /// Number of pixel edges between the component and the rest
+    pub perimeter:usize
+}
+
+impl Connectivity {
+    /// Neighbours visited before a pixel in raster order
+    fn previous(&self)->&'static [(isize,isize)] {
+	match self {
+	    Self::Four => &[(-1,0),(0,-1)],
+	    Self::Eight => &[(-1,-1),(-1,0),(-1,1),(0,-1)]
+	}
+    }
+}
+
+/// Disjoint-set forest over provisional labels; label 0 is reserved
+pub struct UnionFind {
+    parent:Vec<u32>
+}
+
+impl UnionFind {
+    pub fn new()->Self {
+	Self { parent:vec![0] }
+    }
+
+    pub fn len(&self)->usize {
+	self.parent.len()
+    }
+
+    pub fn make(&mut self)->u32 {
+	let l = self.parent.len() as u32;
+	self.parent.push(l);
+	l
+    }
+
+    pub fn find(&mut self,mut l:u32)->u32 {
+	while self.parent[l as usize] != l {
+	    let p = self.parent[self.parent[l as usize] as usize];
+	    self.parent[l as usize] = p;
+	    l = p;
+	}
+	l
+    }
+
+    pub fn union(&mut self,a:u32,b:u32)->u32 {
+	let a = self.find(a);
+	let b = self.find(b);
+	let (r,s) = if a < b { (a,b) } else { (b,a) };
+	self.parent[s as usize] = r;
+	r
+    }
+}
+
+/// Connected components of one layer, as a label image where 0 is
+/// background and component icom has label icom + 1
+pub struct ConnectedComponents {
+    pub labels:Array2<u32>,
+    pub stats:Vec<ComponentStats>
+}
+
+impl ConnectedComponents {
+    /// Two-pass scanline labelling of the pixels of a having the
+    /// given mask bits set
+    pub fn from_array(a:&Array2<u16>,mask:u16,conn:Connectivity)->Res<Self> {
+	let (ny,nx) = a.dim();
+	check_dim(ny,nx)?;
+	let cell = |iy:usize,ix:usize| CellId { iy:iy as i32,ix:ix as i32 };
+	let mut labels : Array2<u32> = Array2::zeros((ny,nx));
+	let mut uf = UnionFind::new();
+
+	for iy in 0..ny {
+	    for ix in 0..nx {
+		if a[[iy,ix]] & mask == 0 {
+		    continue;
+		}
+		let mut l = 0;
+		for &(dy,dx) in conn.previous() {
+		    let jy = iy as isize + dy;
+		    let jx = ix as isize + dx;
+		    if jy < 0 || jx < 0 || jx >= nx as isize {
+			continue;
+		    }
+		    let m = labels[[jy as usize,jx as usize]];
+		    if m > 0 {
+			l = if l == 0 || l == m { m } else { uf.union(l,m) };
+		    }
+		}
+		labels[[iy,ix]] = if l == 0 { uf.make() } else { l };
+	    }
+	}
+
+	// Final labels are numbered in raster order of first pixel
+	let mut final_label = vec![0;uf.parent.len()];
+	let mut stats : Vec<ComponentStats> = Vec::new();
+	for iy in 0..ny {
+	    for ix in 0..nx {
+		let l = labels[[iy,ix]];
+		if l == 0 {
+		    continue;
+		}
+		let r = uf.find(l) as usize;
+		if final_label[r] == 0 {
+		    stats.push(ComponentStats {
+			area:0,
+			min:cell(iy,ix),
+			max:cell(iy,ix),
+			perimeter:0
+		    });
+		    final_label[r] = stats.len() as u32;
+		}
+		let f = final_label[r];
+		labels[[iy,ix]] = f;
+
+		let st = &mut stats[f as usize - 1];
+		st.area += 1;
+		st.min.iy = st.min.iy.min(iy as i32);
+		st.min.ix = st.min.ix.min(ix as i32);
+		st.max.iy = st.max.iy.max(iy as i32);
+		st.max.ix = st.max.ix.max(ix as i32);
+		for c in cell(iy,ix).neighbours() {
+		    if c.iy < 0 || c.ix < 0 ||
+			c.iy as usize >= ny || c.ix as usize >= nx ||
+			a[[c.iy as usize,c.ix as usize]] & mask == 0 {
+			st.perimeter += 1;
+		    }
+		}
+	    }
+	}
+
+	Ok(Self {
+	    labels,
+	    stats
+	})
+    }
+
+    pub fn len(&self)->usize {
+	self.stats.len()
+    }
+
+    /// Number of components that 8-connectivity would merge, from the
+    /// diagonal contacts between pixels of different labels
+    pub fn diagonal_merges(&self)->usize {
+	let (ny,nx) = self.labels.dim();
+	let mut uf = UnionFind::new();
+	for _ in 0..self.len() {
+	    uf.make();
+	}
+	let mut merges = 0;
+	for iy in 1..ny {
+	    for ix in 0..nx {
+		let a = self.labels[[iy,ix]];
+		if a == 0 {
+		    continue;
+		}
+		for jx in [ix.wrapping_sub(1),ix + 1] {
+		    if jx >= nx {
+			continue;
+		    }
+		    let b = self.labels[[iy - 1,jx]];
+		    if b > 0 && uf.find(a) != uf.find(b) {
+			uf.union(a,b);
+			merges += 1;
+		    }
+		}
+	    }
+	}
+	merges
+    }
+
+    pub fn dump(&self) {
+	for (icom,st) in self.stats.iter().enumerate() {
+	    println!("  {:05} {:10}",icom,st.area);
+	}
+    }
+}
+
+/// Region shared by a pair of components of two layers
+#[derive(Clone,Debug,Default)]
+pub struct Overlap {
+    /// Area in pixels
+    pub area:Real,
+    /// Number of pixel edges between the region and pixels outside
+    /// it, not counting the bitmap boundary
+    pub perimeter:usize
+}
+
+impl Overlap {
+    pub fn merge(&mut self,other:&Self) {
+	self.area += other.area;
+	self.perimeter += other.perimeter;
+    }
+}
+
+/// Pixels where copper of the layers strictly between ilay and jlay
+/// shields them from each other, or None for adjacent layers
+pub fn shield(ccs:&[ConnectedComponents],ilay:usize,jlay:usize)->Option<Array2<bool>> {
+    if jlay <= ilay + 1 {
+	return None;
+    }
+    let mut s = ccs[ilay + 1].labels.mapv(|l| l > 0);
+    for cc in ccs[ilay + 2..jlay].iter() {
+	ndarray::Zip::from(&mut s).and(&cc.labels)
+	    .for_each(|s,&l| *s |= l > 0);
+    }
+    Some(s)
+}
+
+/// Regions shared by each pair of components of two layers, indexed
+/// by component indices, except where shielded.  Pixels are weighted
+/// by the product of the coverages (out of 255) of the layers that
+/// have one.
+pub fn overlaps(a:&ConnectedComponents,b:&ConnectedComponents,
+		ca:Option<&Array2<u8>>,cb:Option<&Array2<u8>>,
+		shield:Option<&Array2<bool>>)
+		->BTreeMap<(usize,usize),Overlap> {
+    let (ny,nx) = a.labels.dim();
+    let both = |iy:usize,ix:usize| a.labels[[iy,ix]] > 0 && b.labels[[iy,ix]] > 0 &&
+	!shield.map(|s| s[[iy,ix]]).unwrap_or(false);
+    let mut res : BTreeMap<(usize,usize),Overlap> = BTreeMap::new();
+    for ((iy,ix),&la) in a.labels.indexed_iter() {
+	let lb = b.labels[[iy,ix]];
+	if both(iy,ix) {
+	    let w = |c:Option<&Array2<u8>>|
+		c.map(|c| c[[iy,ix]] as Real / 255.0).unwrap_or(1.0);
+	    let ov = res.entry((la as usize - 1,lb as usize - 1)).or_default();
+	    ov.area += w(ca) * w(cb);
+	    // Neighbours on copper of both layers belong to the same
+	    // components, so only the others are on the boundary
+	    let cell = CellId { iy:iy as i32,ix:ix as i32 };
+	    for c in cell.neighbours() {
+		if 0 <= c.iy && 0 <= c.ix && (c.iy as usize) < ny && (c.ix as usize) < nx &&
+		    !both(c.iy as usize,c.ix as usize) {
+		    ov.perimeter += 1;
+		}
+	    }
+	}
+    }
+    res
+}
+
+#[cfg(test)]
+#[test]
+fn test_labelling() {
+    // A U shape whose arms only join at the bottom, an isolated
+    // pixel and a diagonal neighbour that is not 4-connected
+    let a : Array2<u16> = ndarray::arr2(&[
+	[1,0,1,0,0],
+	[1,0,1,0,1],
+	[1,1,1,0,0],
+	[0,0,0,1,0]
+    ]);
+    let cc = ConnectedComponents::from_array(&a,1,Connectivity::Four).unwrap();
+    assert_eq!(cc.len(),3);
+    assert_eq!(cc.labels[[0,0]],1);
+    assert_eq!(cc.labels[[0,2]],1);
+    assert_eq!(cc.labels[[1,4]],2);
+    assert_eq!(cc.labels[[3,3]],3);
+    assert_eq!(cc.stats[0].area,7);
+    assert_eq!(cc.stats[0].min,CellId { iy:0,ix:0 });
+    assert_eq!(cc.stats[0].max,CellId { iy:2,ix:2 });
+    assert_eq!(cc.stats[0].perimeter,16);
+    assert_eq!(cc.stats[2].perimeter,4);
+    assert_eq!(cc.diagonal_merges(),1);
+
+    let cc = ConnectedComponents::from_array(&a,1,Connectivity::Eight).unwrap();
+    assert_eq!(cc.len(),2);
+    assert_eq!(cc.labels[[3,3]],1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_overlaps() {
+    // A 5 x 6 rectangle over a plate, with copper of the layer
+    // between them shielding all but its first column
+    let a = Array2::from_shape_fn((10,12),|(iy,ix)| {
+	let rect = (2..7).contains(&iy) && (3..9).contains(&ix);
+	let shield = rect && ix > 3;
+	let plate = (1..9).contains(&iy) && (1..11).contains(&ix);
+	rect as u16 | (shield as u16) << 1 | (plate as u16) << 2
+    });
+    let ccs : Vec<ConnectedComponents> = (0..3)
+	.map(|ilay| ConnectedComponents::from_array(&a,1 << ilay,Connectivity::Four).unwrap())
+	.collect();
+    let ov = overlaps(&ccs[0],&ccs[2],None,None,None);
+    assert_eq!(ov.len(),1);
+    assert_eq!(ov[&(0,0)].area,30.0);
+    assert_eq!(ov[&(0,0)].perimeter,2*(5 + 6));
+
+    assert!(shield(&ccs,0,1).is_none());
+    let s = shield(&ccs,0,2);
+    let ov = overlaps(&ccs[0],&ccs[2],None,None,s.as_ref());
+    assert_eq!(ov[&(0,0)].area,5.0);
+    assert_eq!(ov[&(0,0)].perimeter,2*(5 + 1));
+}
@@ -21,11 +21,385 @@ pub struct Rectangle {
     pub p1:Point
 }
 
+/// Pixel channel examined when binarizing a layer bitmap
+#[derive(Clone,Copy,Serialize,Deserialize,Debug,PartialEq,Eq)]
+pub enum Channel {
+    Luma,
+    Red,
+    Green,
+    Blue,
+    Alpha
+}
+
+/// Handling of the alpha channel of layer bitmaps, applied after
+/// inversion
+#[derive(Clone,Copy,Serialize,Deserialize,Debug,PartialEq,Eq)]
+pub enum AlphaMode {
+    /// Alpha is not used
+    Ignore,
+    /// Pixels less than half opaque have no copper
+    Mask,
+    /// Values are scaled by opacity, as if composited over black
+    Premultiply
+}
+
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Binarization {
+    /// Pixels whose value exceeds the threshold have copper
+    pub threshold:u8,
+    /// Invert values, for negative exports
+    pub invert:bool,
+    pub channel:Channel,
+    pub alpha:AlphaMode,
+    /// Take values as the fraction of the pixel covered by copper,
+    /// for anti-aliased renders; pixels above the threshold still
+    /// decide connectivity
+    pub coverage:bool
+}
+
+impl Default for Binarization {
+    fn default()->Self {
+	Self {
+	    threshold:0,
+	    invert:false,
+	    channel:Channel::Luma,
+	    alpha:AlphaMode::Ignore,
+	    coverage:false
+	}
+    }
+}
+
+/// Pixel adjacency used to form copper components
+#[derive(Clone,Copy,Default,Serialize,Deserialize,Debug,PartialEq,Eq)]
+pub enum Connectivity {
+    /// Orthogonal neighbours only
+    #[default]
+    Four,
+    /// Orthogonal and diagonal neighbours
+    Eight
+}
+
+/// Horizontal and vertical resolution, in dots per inch
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct Resolution {
+    pub x:Real,
+    pub y:Real
+}
+
 #[derive(Clone,Serialize,Deserialize,Debug)]
 pub struct Layer {
     pub name:String,
     pub bitmap:String,
     pub gerber:String,
+    #[serde(default)]
+    pub binarization:Binarization,
+    /// Overrides the global connectivity for this layer
+    #[serde(default)]
+    pub connectivity:Option<Connectivity>,
+    /// Gerber position of the bottom-left corner of this bitmap,
+    /// if it differs from the global origin
+    #[serde(default)]
+    pub origin:Option<Point>,
+    /// Resolution of this bitmap, if it differs from the global dpi
+    #[serde(default)]
+    pub dpi:Option<Resolution>,
+    /// Growth of the copper outline of the finished board over the
+    /// artwork, in micrometers; negative for etch loss
+    #[serde(default)]
+    pub etch_offset:Real
+}
+
+impl Layer {
+    /// True if the bitmap must be resampled onto the common grid
+    pub fn has_own_geometry(&self)->bool {
+	self.origin.is_some() || self.dpi.is_some()
+    }
+
+    /// Origin and resolution of the bitmap, given the global ones
+    pub fn geometry(&self,origin:&Point,dpi:Real)->(Point,Resolution) {
+	(self.origin.clone().unwrap_or_else(|| origin.clone()),
+	 self.dpi.clone().unwrap_or(Resolution { x:dpi,y:dpi }))
+    }
+}
+
+/// Group of nets given by regular expressions matching their whole
+/// names
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct NetClass {
+    pub name:String,
+    pub patterns:Vec<String>,
+    /// Leave the nets out of the estimate, like N/C
+    #[serde(default)]
+    pub ignore:bool,
+    /// Reference planes: capacitances between two reference nets
+    /// are not reported
+    #[serde(default)]
+    pub reference:bool,
+    /// Merge the nets into a single conductor named after the class
+    #[serde(default)]
+    pub aggregate:bool
+}
+
+/// Handling of components on which flash points of several nets land
+#[derive(Clone,Copy,Default,Serialize,Deserialize,Debug,PartialEq,Eq)]
+pub enum ShortPolicy {
+    /// Stop after writing the reports
+    Error,
+    /// Name the component after the net of its first flash point in
+    /// the Gerber file
+    #[default]
+    KeepFirst,
+    /// Name the component after all its nets, joined by |
+    Ambiguous
+}
+
+/// Handling of components that no flash point names
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Islands {
+    /// Unnamed components of at least this area, in square
+    /// millimeters, are floating conductors with their own net
+    pub floating_area:Option<Real>,
+    /// Number of largest unnamed components of each layer for which
+    /// a thumbnail is saved
+    pub thumbnails:usize
+}
+
+impl Default for Islands {
+    fn default()->Self {
+	Self {
+	    floating_area:None,
+	    thumbnails:20
+	}
+    }
+}
+
+/// Matching of flash points that miss the copper
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Matching {
+    /// Search radius for the nearest copper pixel, in millimeters
+    pub radius:Real,
+    /// Matches farther than this, in millimeters, are warned about
+    pub warn_distance:Real
+}
+
+impl Default for Matching {
+    fn default()->Self {
+	Self {
+	    radius:0.0,
+	    warn_distance:0.05
+	}
+    }
+}
+
+/// Parameters of the automatic origin and resolution registration
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Registration {
+    /// Half-width of the origin search window, in millimeters
+    pub range:Real,
+    /// Relative tolerance on the resolution
+    pub dpi_tolerance:Real,
+    /// Radius of the disk used to locate pad centroids, in millimeters
+    pub radius:Real
+}
+
+impl Default for Registration {
+    fn default()->Self {
+	Self {
+	    range:2.0,
+	    dpi_tolerance:0.002,
+	    radius:1.0
+	}
+    }
+}
+
+/// Parameters of the inter-layer alignment check
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Alignment {
+    /// Radius of the windows around common pads, in millimeters
+    pub radius:Real,
+    /// Largest offset searched, in millimeters
+    pub max_shift:Real,
+    /// Offset above which a warning is issued, in millimeters
+    pub tolerance:Real
+}
+
+impl Default for Alignment {
+    fn default()->Self {
+	Self {
+	    radius:1.0,
+	    max_shift:0.25,
+	    tolerance:0.05
+	}
+    }
+}
+
+/// Drill file whose holes remove copper from every layer
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct Drill {
+    /// Excellon or Gerber drill file under the input directory
+    pub file:String,
+    /// Thickness of the hole wall plating, in micrometers; holes are
+    /// subtracted with their finished diameter
+    #[serde(default)]
+    pub plating:Real,
+    /// Flash points of a net within this distance of the wall of a
+    /// hole, in millimeters, are joined through it when checking for
+    /// opens; None for unplated holes
+    #[serde(default)]
+    pub via_tolerance:Option<Real>
+}
+
+/// Dielectric between two consecutive copper layers
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct Dielectric {
+    /// Thickness in millimeters
+    pub thickness:Real,
+    pub eps_rel:Real,
+    #[serde(default)]
+    pub material:Option<String>
+}
+
+/// Correction for the fringing fields at the edges of overlaps
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct Fringing {
+    /// Copper thickness in micrometers
+    pub copper:Real
+}
+
+/// Coupling between facing edges of traces of the same layer
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Coplanar {
+    /// Largest gap between edges, in millimeters
+    pub distance:Real,
+    /// Largest trace width taken into account, in millimeters
+    pub width_max:Real
+}
+
+impl Default for Coplanar {
+    fn default()->Self {
+	Self {
+	    distance:0.5,
+	    width_max:2.0
+	}
+    }
+}
+
+/// Finite-difference field solution for a subset of the nets
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct FieldSolver {
+    /// Nets, or aggregated classes, to excite in turn
+    pub nets:Vec<String>,
+    /// Lateral cell size in millimeters, by default the pixel size
+    pub cell:Option<Real>,
+    /// Number of cells across each dielectric
+    pub z_cells:usize,
+    /// Thickness of air above and below the board, in millimeters
+    pub margin:Real,
+    /// Copper kept around the selected nets, in millimeters
+    pub lateral_margin:Real,
+    /// Largest number of grid nodes
+    pub max_nodes:usize,
+    /// Relative residual at which iterations stop
+    pub tolerance:Real,
+    pub max_iterations:usize
+}
+
+impl Default for FieldSolver {
+    fn default()->Self {
+	Self {
+	    nets:Vec::new(),
+	    cell:None,
+	    z_cells:4,
+	    margin:1.0,
+	    lateral_margin:2.0,
+	    max_nodes:20_000_000,
+	    tolerance:1e-6,
+	    max_iterations:200
+	}
+    }
+}
+
+/// Floating random walk extraction for a subset of the nets
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct RandomWalk {
+    /// Nets, or aggregated classes, whose capacitances are estimated
+    pub nets:Vec<String>,
+    /// Relative half-width of the confidence interval of the total
+    /// capacitance of each net at which walks stop
+    pub error:Real,
+    /// Confidence level of the intervals
+    pub confidence:Real,
+    /// Thickness of air above and below the board, in millimeters
+    pub margin:Real,
+    /// Largest number of walks per net, including those left out
+    pub max_walks:usize,
+    /// Seed of the random number generators, for repeatable results
+    pub seed:Option<u64>
+}
+
+impl Default for RandomWalk {
+    fn default()->Self {
+	Self {
+	    nets:Vec::new(),
+	    error:0.01,
+	    confidence:0.95,
+	    margin:1.0,
+	    max_walks:10_000_000,
+	    seed:None
+	}
+    }
+}
+
+/// Two-dimensional field solution across the board along a cut line
+#[derive(Clone,Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct CrossSection {
+    /// Ends of the cut line, in Gerber millimeters
+    pub from:Point,
+    pub to:Point,
+    /// Distance between samples along the line in millimeters, by
+    /// default the pixel size
+    pub step:Option<Real>,
+    /// Number of cells across each dielectric
+    pub z_cells:usize,
+    /// Thickness of air above and below the board, in millimeters
+    pub margin:Real,
+    /// Relative residual at which iterations stop
+    pub tolerance:Real,
+    pub max_iterations:usize
+}
+
+impl Default for CrossSection {
+    fn default()->Self {
+	Self {
+	    from:Point { x:0.0,y:0.0 },
+	    to:Point { x:0.0,y:0.0 },
+	    step:None,
+	    z_cells:8,
+	    margin:1.0,
+	    tolerance:1e-8,
+	    max_iterations:500
+	}
+    }
+}
+
+/// Parameters of the out-of-core processing of large bitmaps in
+/// bands of full-width rows
+#[derive(Clone,Serialize,Deserialize,Debug)]
+pub struct Bands {
+    /// Memory used for bitmap bands, in megabytes.  This does not
+    /// bound the bookkeeping of components (the pieces of each band,
+    /// their extents, overlaps and flash point matches), which grows
+    /// with the number of components of the board.
+    pub memory:Real
 }
 
 #[derive(Clone,Serialize,Deserialize,Debug)]
@@ -39,7 +413,87 @@ pub struct Config {
     pub dpi:Real,
     pub eps_rel:Real,
     pub thickness:Real,
-    pub cap_min:Real
+    pub cap_min:Real,
+    /// Also write the Maxwell matrix with all its entries
+    #[serde(default)]
+    pub maxwell_dense:bool,
+    #[serde(default)]
+    pub connectivity:Connectivity,
+    #[serde(default)]
+    pub registration:Registration,
+    #[serde(default)]
+    pub alignment:Alignment,
+    #[serde(default)]
+    #[serde(alias = "tiling")]
+    pub bands:Option<Bands>,
+    #[serde(default)]
+    pub drills:Vec<Drill>,
+    #[serde(default)]
+    pub shorts:ShortPolicy,
+    #[serde(default)]
+    pub islands:Islands,
+    #[serde(default)]
+    pub matching:Matching,
+    #[serde(default)]
+    pub net_classes:Vec<NetClass>,
+    #[serde(default)]
+    pub stackup:Vec<Dielectric>,
+    #[serde(default)]
+    pub fringing:Option<Fringing>,
+    /// Largest distance between coupled layers, or None for any
+    #[serde(default)]
+    pub layer_span:Option<usize>,
+    #[serde(default)]
+    pub coplanar:Option<Coplanar>,
+    #[serde(default)]
+    pub field:Option<FieldSolver>,
+    #[serde(default)]
+    pub random_walk:Option<RandomWalk>,
+    #[serde(default)]
+    pub cross_section:Option<CrossSection>
+}
+
+impl Config {
+    /// Dielectric between layers ilay and ilay + 1, from the stackup
+    /// if given, otherwise from the global thickness and eps_rel
+    pub fn dielectric(&self,ilay:usize)->Dielectric {
+	self.stackup.get(ilay).cloned().unwrap_or(Dielectric {
+	    thickness:self.thickness,
+	    eps_rel:self.eps_rel,
+	    material:None
+	})
+    }
+
+    /// Dielectrics between layers ilay and jlay > ilay in series, as
+    /// one of the same capacitance per unit area
+    pub fn dielectric_between(&self,ilay:usize,jlay:usize)->Dielectric {
+	if jlay == ilay + 1 {
+	    return self.dielectric(ilay);
+	}
+	let mut thickness = 0.0;
+	let mut resistance = 0.0;
+	for k in ilay..jlay {
+	    let d = self.dielectric(k);
+	    thickness += d.thickness;
+	    resistance += d.thickness / d.eps_rel;
+	}
+	Dielectric {
+	    thickness,
+	    eps_rel:thickness / resistance,
+	    material:None
+	}
+    }
+
+    /// Pairs of layers (ilay,jlay), ilay < jlay, between which
+    /// coupling is estimated
+    pub fn layer_pairs(&self)->Vec<(usize,usize)> {
+	let nlay = self.layers.len();
+	let span = self.layer_span.unwrap_or(nlay).max(1);
+	(0..nlay)
+	    .flat_map(|ilay| (ilay + 1..nlay.min(ilay + span + 1))
+		      .map(move |jlay| (ilay,jlay)))
+	    .collect()
+    }
 }
 
 pub trait Loadable {
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    math::*,
+    config::Config
+};
+
+/// Facing edges between two components of a layer
+#[derive(Clone,Debug,Default)]
+pub struct Coupling {
+    /// Parallel run length in pixels
+    pub run:usize,
+    /// Sum of the gaps along the run, in pixels
+    pub gap:Real,
+    /// Sum along the run of the capacitance per unit length of
+    /// coplanar strips, relative to the permittivity
+    pub factor:Real
+}
+
+/// Arithmetic-geometric mean
+fn agm(mut a:Real,mut b:Real)->Real {
+    while (a - b).abs() > 1e-15 * a {
+	(a,b) = (0.5 * (a + b),sqrt(a * b));
+    }
+    a
+}
+
+/// Ratio K(k')/K(k) of complete elliptic integrals of the first
+/// kind, with k' = sqrt(1 - k^2)
+fn elliptic_ratio(k:Real)->Real {
+    agm(1.0,sqrt(1.0 - k*k)) / agm(1.0,k)
+}
+
+/// Capacitance per unit length of coplanar strips of width w
+/// separated by a gap s, relative to the permittivity, by conformal
+/// mapping
+pub fn strips(s:Real,w:Real)->Real {
+    elliptic_ratio(s / (s + 2.0*w))
+}
+
+/// Effective relative permittivity around the traces of a layer:
+/// half air for the outer layers, otherwise the mean of the
+/// dielectrics on both sides
+pub fn eps_eff(config:&Config,ilay:usize)->Real {
+    let nlay = config.layers.len();
+    let above = if ilay > 0 { Some(config.dielectric(ilay - 1).eps_rel) } else { None };
+    let below = if ilay + 1 < nlay { Some(config.dielectric(ilay).eps_rel) } else { None };
+    match (above,below) {
+	(Some(a),Some(b)) => 0.5 * (a + b),
+	(Some(e),None) | (None,Some(e)) => 0.5 * (e + 1.0),
+	(None,None) => 0.5 * (config.eps_rel + 1.0)
+    }
+}
+
+/// Edges of differently named components of a layer that face each
+/// other across at most distance empty pixels, along rows and
+/// columns, indexed by ordered component indices.  Strip widths are
+/// measured behind the edges up to width_max pixels.
+pub fn couplings(labels:&Array2<u32>,names:&[Option<String>],
+		 distance:usize,width_max:usize)
+		 ->BTreeMap<(usize,usize),Coupling> {
+    let (ny,nx) = labels.dim();
+    let label = |iy:isize,ix:isize| {
+	if 0 <= iy && iy < ny as isize && 0 <= ix && ix < nx as isize {
+	    labels[[iy as usize,ix as usize]]
+	} else {
+	    0
+	}
+    };
+    let width = |iy:isize,ix:isize,dy:isize,dx:isize,l:u32| {
+	(0..width_max as isize)
+	    .take_while(|&k| label(iy + k*dy,ix + k*dx) == l)
+	    .count()
+    };
+    let mut res : BTreeMap<(usize,usize),Coupling> = BTreeMap::new();
+    for ((iy,ix),&la) in labels.indexed_iter() {
+	if la == 0 {
+	    continue;
+	}
+	let (iy,ix) = (iy as isize,ix as isize);
+	for (dy,dx) in [(0,1),(1,0)] {
+	    let mut k = 1;
+	    while k <= distance as isize + 1 && label(iy + k*dy,ix + k*dx) == 0 {
+		k += 1;
+	    }
+	    let (jy,jx) = (iy + k*dy,ix + k*dx);
+	    let lb = label(jy,jx);
+	    if k == 1 || lb == 0 || lb == la {
+		continue;
+	    }
+	    let (ia,ib) = (la as usize - 1,lb as usize - 1);
+	    match (&names[ia],&names[ib]) {
+		(Some(u),Some(v)) if u != v => (),
+		_ => continue
+	    }
+	    let wa = width(iy,ix,-dy,-dx,la);
+	    let wb = width(jy,jx,dy,dx,lb);
+	    let gap = (k - 1) as Real;
+	    let c = res.entry((ia.min(ib),ia.max(ib))).or_default();
+	    c.run += 1;
+	    c.gap += gap;
+	    c.factor += strips(gap,0.5 * (wa + wb) as Real);
+	}
+    }
+    res
+}
+
+#[cfg(test)]
+#[test]
+fn test_elliptic_ratio() {
+    // K(1/sqrt 2) = K'(1/sqrt 2); K(1/2) = 1.685750, K(sqrt 3/2) = 2.156516
+    assert!((elliptic_ratio(0.5_f64.sqrt()) - 1.0).abs() < 1e-12);
+    assert!((elliptic_ratio(0.5) - 2.156516/1.685750).abs() < 1e-6);
+}
@@ -49,7 +49,7 @@ impl Iterator for DiskIterator {
 	    } else {
 		if i < self.i1 {
 		    i += 1;
-		    j = 0;
+		    j = self.j0;
 		} else {
 		    return None;
 		}
@@ -66,3 +66,24 @@ impl Iterator for DiskIterator {
 	}
     }
 }
+
+/// Pixel nearest to the fractional indices (ic,jc) of an array of
+/// ni x nj pixels, within r pixels, for which the predicate holds,
+/// with its distance in pixels
+pub fn nearest<F:Fn(usize,usize)->bool>(ic:Real,jc:Real,r:Real,ni:usize,nj:usize,
+					 pred:F)->Option<(usize,usize,Real)> {
+    let mut best : Option<(usize,usize,Real)> = None;
+    for (i,j) in DiskIterator::new(ic + 0.5,jc + 0.5,r,
+				   0,ni as isize - 1,
+				   0,nj as isize - 1) {
+	let (i,j) = (i as usize,j as usize);
+	if !pred(i,j) {
+	    continue;
+	}
+	let d = hypot(i as Real - ic,j as Real - jc);
+	if best.map(|(_,_,e)| d < e).unwrap_or(true) {
+	    best = Some((i,j,d));
+	}
+    }
+    best
+}
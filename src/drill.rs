@@ -0,0 +1,241 @@
+use log::{info,warn};
+use std::collections::BTreeMap;
+use regex::Regex;
+
+use crate::{
+    common::*,
+    math::*,
+    config::{Config,Drill},
+    disk::DiskIterator,
+    gerber::{self,Command,Image,Mode,Operation},
+    grid::Grid
+};
+
+/// Drilled hole, in millimeters
+#[derive(Clone,Debug)]
+pub struct Hole {
+    pub x:Real,
+    pub y:Real,
+    /// Finished diameter
+    pub diameter:Real,
+    /// Distance beyond the hole wall within which the hole joins the
+    /// flash points of a net as a via, if it is plated
+    pub via_tolerance:Option<Real>
+}
+
+/// Loads the holes of all drill files of the configuration
+pub fn load_all(config:&Config)->Res<Vec<Hole>> {
+    let mut holes = Vec::new();
+    for d in config.drills.iter() {
+	let path = format!("{}/{}",config.input,d.file);
+	let mut h = load(&path,d)?;
+	info!("Loaded {} holes from {}",h.len(),path);
+	holes.append(&mut h);
+    }
+    Ok(holes)
+}
+
+/// Loads an Excellon or Gerber drill file
+pub fn load(path:&str,d:&Drill)->Res<Vec<Hole>> {
+    let u = std::fs::read_to_string(path)?;
+    let mut holes = if u.contains("%FS") || u.contains("%AD") {
+	gerber_holes(&Image::parse(&u)?)
+    } else {
+	excellon(&u)?
+    };
+    for h in holes.iter_mut() {
+	h.diameter -= 2.0 * d.plating * 1e-3;
+	h.via_tolerance = d.via_tolerance;
+    }
+    holes.retain(|h| h.diameter > 0.0);
+    Ok(holes)
+}
+
+/// Flashes of circular apertures of a drill Gerber file
+pub fn gerber_holes(img:&Image)->Vec<Hole> {
+    let mut scale = 1.0;
+    let mut x_cf = gerber::CoordinateFormat::default();
+    let mut y_cf = gerber::CoordinateFormat::default();
+    let mut apertures : BTreeMap<u32,Real> = BTreeMap::new();
+    let mut aperture = None;
+    let mut holes = Vec::new();
+    for cmd in &img.commands {
+	match cmd {
+	    Command::SetMode(Mode::Inches) => scale = 25.4,
+	    Command::SetMode(Mode::Millimeters) => scale = 1.0,
+	    &Command::SetCoordinateFormat { x,y } => {
+		x_cf = x;
+		y_cf = y;
+	    },
+	    Command::DefineAperture { code,template,params }
+	    if template == "C" && !params.is_empty() => {
+		apertures.insert(*code,params[0] * scale);
+	    },
+	    &Command::SetAperture(code) => aperture = apertures.get(&code).copied(),
+	    &Command::Operation { op:Operation::Flash,x,y } => {
+		if let Some(diameter) = aperture {
+		    holes.push(Hole {
+			x:x_cf.convert(x) * scale,
+			y:y_cf.convert(y) * scale,
+			diameter,
+			via_tolerance:None
+		    });
+		}
+	    },
+	    _ => ()
+	}
+    }
+    holes
+}
+
+/// Value of an Excellon coordinate; without a decimal point, the
+/// digits have the given numbers of integer and decimal places, and
+/// with leading zeros kept the trailing ones are omitted
+fn coordinate(u:&str,int:usize,dec:usize,leading_zeros:bool)->Res<Real> {
+    if u.contains('.') {
+	return Ok(u.parse()?);
+    }
+    let (sign,digits) = match u.strip_prefix('-') {
+	Some(d) => (-1.0,d),
+	None => (1.0,u.trim_start_matches('+'))
+    };
+    let mut v : Real = digits.parse()?;
+    if leading_zeros {
+	v *= 10.0_f64.powi((int + dec) as i32 - digits.len() as i32);
+    }
+    Ok(sign * v / 10.0_f64.powi(dec as i32))
+}
+
+/// Drill hits of an Excellon file; slots are ignored
+pub fn excellon(u:&str)->Res<Vec<Hole>> {
+    let tool_rex = Regex::new(r"^T([0-9]+)(?:[FSB][0-9.]+)*C([0-9.]+)")?;
+    let select_rex = Regex::new(r"^T([0-9]+)$")?;
+    let hit_rex = Regex::new(r"^(?:X([+-]?[0-9.]+))?(?:Y([+-]?[0-9.]+))?$")?;
+
+    let mut scale = 1.0;
+    let (mut int,mut dec) = (3,3);
+    let mut leading_zeros = true;
+    let mut tools : BTreeMap<u32,Real> = BTreeMap::new();
+    let mut tool = None;
+    let (mut x,mut y) = (0.0,0.0);
+    let mut header = false;
+    let mut n_slots = 0;
+    let mut holes = Vec::new();
+    for line in u.lines() {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with(';') {
+	    continue;
+	}
+	match line {
+	    "M48" => header = true,
+	    "%" | "M95" => header = false,
+	    "M71" => scale = 1.0,
+	    "M72" => scale = 25.4,
+	    _ if line.starts_with("METRIC") || line.starts_with("INCH") => {
+		let inch = line.starts_with("INCH");
+		scale = if inch { 25.4 } else { 1.0 };
+		(int,dec) = if inch { (2,4) } else { (3,3) };
+		for f in line.split(',').skip(1) {
+		    match f {
+			"LZ" => leading_zeros = true,
+			"TZ" => leading_zeros = false,
+			_ => if let Some((i,d)) = f.split_once('.') {
+			    (int,dec) = (i.len(),d.len());
+			}
+		    }
+		}
+	    },
+	    _ if line.contains("G85") => n_slots += 1,
+	    _ => {
+		if let Some(caps) = tool_rex.captures(line) {
+		    let t : u32 = caps[1].parse()?;
+		    let d : Real = caps[2].parse()?;
+		    tools.insert(t,d * scale);
+		    if !header {
+			tool = Some(t);
+		    }
+		} else if let Some(caps) = select_rex.captures(line) {
+		    let t : u32 = caps[1].parse()?;
+		    tool = if t == 0 { None } else { Some(t) };
+		} else if let Some(caps) = hit_rex.captures(line) {
+		    if header {
+			continue;
+		    }
+		    if let Some(u) = caps.get(1) {
+			x = coordinate(u.as_str(),int,dec,leading_zeros)? * scale;
+		    }
+		    if let Some(u) = caps.get(2) {
+			y = coordinate(u.as_str(),int,dec,leading_zeros)? * scale;
+		    }
+		    let diameter = tool
+			.and_then(|t| tools.get(&t))
+			.copied()
+			.ok_or_else(|| error(&format!("Drill hit {} without a tool",
+						      line)))?;
+		    holes.push(Hole { x,y,diameter,via_tolerance:None });
+		}
+	    }
+	}
+    }
+    if n_slots > 0 {
+	warn!("Ignored {} drilled slots",n_slots);
+    }
+    Ok(holes)
+}
+
+/// Removes the holes from labels covering the rows of the grid
+/// starting at iy0
+pub fn clear(labels:&mut Array2<u32>,grid:&Grid,holes:&[Hole],iy0:usize) {
+    let (ny,nx) = labels.dim();
+    for h in holes.iter() {
+	let (iyf,ixf) = grid.index(h.x,h.y);
+	let iyf = iyf - iy0 as Real;
+	let r = 0.5 * h.diameter / grid.delta;
+	if iyf + r < -1.0 || iyf - r > ny as Real {
+	    continue;
+	}
+	for (iy,ix) in DiskIterator::new(iyf + 0.5,ixf + 0.5,r,
+					 0,ny as isize - 1,
+					 0,nx as isize - 1) {
+	    labels[[iy as usize,ix as usize]] = 0;
+	}
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_excellon() {
+    let close = |h:&Hole,x:Real,y:Real,d:Real|
+	(h.x - x).abs() < 1e-9 && (h.y - y).abs() < 1e-9 && (h.diameter - d).abs() < 1e-9;
+
+    // Metric with leading zeros kept; a slot, a coordinate kept from
+    // the previous hit and a tool defined in the body
+    let holes = excellon("M48\n\
+			  ; comment\n\
+			  METRIC,LZ,000.000\n\
+			  T1C0.300\n\
+			  T2F200S1000C1.000\n\
+			  %\n\
+			  T1\n\
+			  X010000Y020000\n\
+			  Y025000\n\
+			  T2\n\
+			  X005Y001\n\
+			  X1.5Y2.5G85X3.5Y2.5\n\
+			  T3C0.5\n\
+			  X-1.25Y2.5\n\
+			  M30\n").unwrap();
+    assert_eq!(holes.len(),4);
+    assert!(close(&holes[0],10.0,20.0,0.3));
+    assert!(close(&holes[1],10.0,25.0,0.3));
+    assert!(close(&holes[2],5.0,1.0,1.0));
+    assert!(close(&holes[3],-1.25,2.5,0.5));
+
+    // Inches with trailing zeros kept, in the default 2.4 format
+    let holes = excellon("M48\nINCH,TZ\nT01C0.04\n%\nT01\nX1000Y-2500\nX+10000\n").unwrap();
+    assert_eq!(holes.len(),2);
+    assert!(close(&holes[0],2.54,-6.35,1.016));
+    assert!(close(&holes[1],25.4,-6.35,1.016));
+
+    assert!(excellon("M48\nMETRIC\n%\nX1.0Y1.0\n").is_err());
+}
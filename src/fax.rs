@@ -0,0 +1,224 @@
+// Decoder for ITU-T T.6 (CCITT Group 4) compressed bilevel images,
+// as found in fax-compressed TIFF files.
+
+use crate::common::*;
+
+const WHITE_TERMINATING : [&str;64] = [
+    "00110101","000111","0111","1000","1011","1100","1110","1111",
+    "10011","10100","00111","01000","001000","000011","110100","110101",
+    "101010","101011","0100111","0001100","0001000","0010111","0000011","0000100",
+    "0101000","0101011","0010011","0100100","0011000","00000010","00000011","00011010",
+    "00011011","00010010","00010011","00010100","00010101","00010110","00010111","00101000",
+    "00101001","00101010","00101011","00101100","00101101","00000100","00000101","00001010",
+    "00001011","01010010","01010011","01010100","01010101","00100100","00100101","01011000",
+    "01011001","01011010","01011011","01001010","01001011","00110010","00110011","00110100"
+];
+
+const WHITE_MAKEUP : [&str;27] = [
+    "11011","10010","010111","0110111","00110110","00110111","01100100","01100101",
+    "01101000","01100111","011001100","011001101","011010010","011010011","011010100","011010101",
+    "011010110","011010111","011011000","011011001","011011010","011011011","010011000","010011001",
+    "010011010","011000","010011011"
+];
+
+const BLACK_TERMINATING : [&str;64] = [
+    "0000110111","010","11","10","011","0011","0010","00011",
+    "000101","000100","0000100","0000101","0000111","00000100","00000111","000011000",
+    "0000010111","0000011000","0000001000","00001100111","00001101000","00001101100","00000110111","00000101000",
+    "00000010111","00000011000","000011001010","000011001011","000011001100","000011001101","000001101000","000001101001",
+    "000001101010","000001101011","000011010010","000011010011","000011010100","000011010101","000011010110","000011010111",
+    "000001101100","000001101101","000011011010","000011011011","000001010100","000001010101","000001010110","000001010111",
+    "000001100100","000001100101","000001010010","000001010011","000000100100","000000110111","000000111000","000000100111",
+    "000000101000","000001011000","000001011001","000000101011","000000101100","000001011010","000001100110","000001100111"
+];
+
+const BLACK_MAKEUP : [&str;27] = [
+    "0000001111","000011001000","000011001001","000001011011","000000110011","000000110100","000000110101","0000001101100",
+    "0000001101101","0000001001010","0000001001011","0000001001100","0000001001101","0000001110010","0000001110011","0000001110100",
+    "0000001110101","0000001110110","0000001110111","0000001010010","0000001010011","0000001010100","0000001010101","0000001011010",
+    "0000001011011","0000001100100","0000001100101"
+];
+
+/// Makeup codes for runs of 1792 to 2560 pixels, common to both colors
+const EXTENDED_MAKEUP : [&str;13] = [
+    "00000001000","00000001100","00000001101","000000010010","000000010011",
+    "000000010100","000000010101","000000010110","000000010111","000000011100",
+    "000000011101","000000011110","000000011111"
+];
+
+const CODE_BITS_MAX : usize = 13;
+
+/// Run lengths indexed by (1 << length) | code
+struct RunCodes {
+    table:Vec<Option<u16>>
+}
+
+impl RunCodes {
+    fn new(terminating:&[&str],makeup:&[&str])->Self {
+	let mut table = vec![None;1 << (CODE_BITS_MAX + 1)];
+	let mut add = |code:&str,run:usize| {
+	    let bits = u32::from_str_radix(code,2).unwrap() as usize;
+	    table[(1 << code.len()) | bits] = Some(run as u16);
+	};
+	for (run,code) in terminating.iter().enumerate() {
+	    add(code,run);
+	}
+	for (k,code) in makeup.iter().enumerate() {
+	    add(code,64*(k + 1));
+	}
+	for (k,code) in EXTENDED_MAKEUP.iter().enumerate() {
+	    add(code,1792 + 64*k);
+	}
+	Self { table }
+    }
+}
+
+struct BitReader<'a> {
+    data:&'a [u8],
+    pos:usize,
+    reverse:bool
+}
+
+impl<'a> BitReader<'a> {
+    fn bit(&mut self)->Res<usize> {
+	let byte = *self.data.get(self.pos >> 3)
+	    .ok_or_else(|| error("Truncated G4 data"))?;
+	let k = self.pos & 7;
+	self.pos += 1;
+	let shift = if self.reverse { k } else { 7 - k };
+	Ok(((byte >> shift) & 1) as usize)
+    }
+
+    /// Reads one run length, made of makeup codes followed by a
+    /// terminating code
+    fn run(&mut self,codes:&RunCodes)->Res<usize> {
+	let mut total = 0;
+	loop {
+	    let mut key = 1;
+	    let run = loop {
+		key = (key << 1) | self.bit()?;
+		if key >= 1 << (CODE_BITS_MAX + 1) {
+		    return Err(error("Invalid G4 run length code"));
+		}
+		if let Some(run) = codes.table[key] {
+		    break run as usize;
+		}
+	    };
+	    total += run;
+	    if run < 64 {
+		return Ok(total);
+	    }
+	}
+    }
+}
+
+enum Mode {
+    Pass,
+    Horizontal,
+    Vertical(isize),
+    End
+}
+
+impl<'a> BitReader<'a> {
+    fn mode(&mut self)->Res<Mode> {
+	let mut zeros = 0;
+	while self.bit()? == 0 {
+	    zeros += 1;
+	    if zeros > 11 {
+		return Err(error("Invalid G4 mode code"));
+	    }
+	}
+	Ok(match zeros {
+	    0 => Mode::Vertical(0),
+	    1 => if self.bit()? == 1 { Mode::Vertical(1) } else { Mode::Vertical(-1) },
+	    2 => Mode::Horizontal,
+	    3 => Mode::Pass,
+	    4 => if self.bit()? == 1 { Mode::Vertical(2) } else { Mode::Vertical(-2) },
+	    5 => if self.bit()? == 1 { Mode::Vertical(3) } else { Mode::Vertical(-3) },
+	    11 => Mode::End,
+	    _ => return Err(error("Unsupported G4 mode code"))
+	})
+    }
+}
+
+/// Decodes height rows of width pixels; black pixels are set to 1.
+/// With reverse, the least significant bit of each byte comes first.
+pub fn decode_g4(data:&[u8],width:usize,height:usize,reverse:bool)
+		 ->Res<Vec<Vec<u8>>> {
+    let white = RunCodes::new(&WHITE_TERMINATING,&WHITE_MAKEUP);
+    let black = RunCodes::new(&BLACK_TERMINATING,&BLACK_MAKEUP);
+    let mut br = BitReader { data,pos:0,reverse };
+
+    // Changing elements of the reference and coding lines; even
+    // entries start black runs, odd entries start white runs
+    let mut reference : Vec<usize> = Vec::new();
+    let mut rows = Vec::with_capacity(height);
+    for _ in 0..height {
+	let mut coding : Vec<usize> = Vec::new();
+	let mut a0 : isize = -1;
+	let mut black_run = false;
+	let mut kb = 0;
+	while a0 < width as isize {
+	    // b1 is the first changing element after a0 of the opposite
+	    // color; a0 never decreases
+	    while kb < reference.len() && reference[kb] as isize <= a0 {
+		kb += 1;
+	    }
+	    let k = if (kb & 1 == 1) == black_run { kb } else { kb + 1 };
+	    let b1 = reference.get(k).copied().unwrap_or(width);
+	    let b2 = reference.get(k + 1).copied().unwrap_or(width);
+	    match br.mode()? {
+		Mode::Pass => a0 = b2 as isize,
+		Mode::Horizontal => {
+		    let start = a0.max(0) as usize;
+		    let (r1,r2) = if black_run {
+			(br.run(&black)?,br.run(&white)?)
+		    } else {
+			(br.run(&white)?,br.run(&black)?)
+		    };
+		    let a1 = (start + r1).min(width);
+		    let a2 = (a1 + r2).min(width);
+		    coding.push(a1);
+		    coding.push(a2);
+		    a0 = a2 as isize;
+		},
+		Mode::Vertical(d) => {
+		    let a1 = (b1 as isize + d).max(0).min(width as isize);
+		    if a1 < a0 {
+			return Err(error("Invalid G4 vertical mode"));
+		    }
+		    coding.push(a1 as usize);
+		    a0 = a1;
+		    black_run = !black_run;
+		},
+		Mode::End => return Err(error("Premature end of G4 data"))
+	    }
+	}
+	let mut row = vec![0;width];
+	for pair in coding.chunks(2) {
+	    let end = pair.get(1).copied().unwrap_or(width);
+	    for v in row[pair[0]..end].iter_mut() {
+		*v = 1;
+	    }
+	}
+	rows.push(row);
+	reference = coding;
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode_g4() {
+    // Two rows of 2 white, 4 black and 2 white pixels: horizontal
+    // mode 001 with runs W2 0111 and B4 011, then vertical mode V0
+    // (1) up to the end of the first row, and V0 three times for
+    // the second one
+    let row = vec![0,0,1,1,1,1,0,0];
+    let rows = decode_g4(&[0x2e,0xfc],8,2,false).unwrap();
+    assert_eq!(rows,vec![row.clone(),row.clone()]);
+    // Same with the least significant bit of each byte first
+    let rows = decode_g4(&[0x74,0x3f],8,2,true).unwrap();
+    assert_eq!(rows,vec![row.clone(),row]);
+    assert!(decode_g4(&[0x2e],8,2,false).is_err());
+}
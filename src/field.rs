@@ -0,0 +1,544 @@
+use log::{info,warn};
+use rayon::prelude::*;
+
+use crate::{
+    common::*,
+    math::*,
+    config::{Config,FieldSolver}
+};
+
+const EPS0 : Real = 8.854e-12;
+
+/// Largest number of columns of the coarsest multigrid level
+const COARSEST_COLUMNS : usize = 16;
+
+/// Symmetric smoothing sweeps on the coarsest level
+const COARSEST_SWEEPS : usize = 20;
+
+/// Ratio of the thicknesses of successive air cells away from the
+/// board
+const AIR_GROWTH : Real = 1.5;
+
+/// Nodes of the solver grid, indexed by ((y * nx) + x) * nz + z so
+/// that vertical columns are contiguous, with the conductances to
+/// their neighbours at x + 1, y + 1 and z + 1, in farads
+struct Mesh {
+    ny:usize,
+    nx:usize,
+    nz:usize,
+    gx:Vec<Real>,
+    gy:Vec<Real>,
+    gz:Vec<Real>,
+    /// Conductor holding each node at a fixed potential
+    fixed:Vec<Option<usize>>
+}
+
+impl Mesh {
+    fn len(&self)->usize {
+	self.ny * self.nx * self.nz
+    }
+
+    fn neighbours(&self,i:usize)->impl Iterator<Item=(usize,Real)> {
+	let (nx,nz) = (self.nx,self.nz);
+	let z = i % nz;
+	let x = (i / nz) % nx;
+	let y = i / (nz * nx);
+	let sy = nx * nz;
+	[
+	    if x > 0 { Some((i - nz,self.gx[i - nz])) } else { None },
+	    if x + 1 < nx { Some((i + nz,self.gx[i])) } else { None },
+	    if y > 0 { Some((i - sy,self.gy[i - sy])) } else { None },
+	    if y + 1 < self.ny { Some((i + sy,self.gy[i])) } else { None },
+	    if z > 0 { Some((i - 1,self.gz[i - 1])) } else { None },
+	    if z + 1 < nz { Some((i + 1,self.gz[i])) } else { None }
+	].into_iter().flatten()
+    }
+}
+
+/// Multigrid level: conductances between free nodes and diagonal of
+/// the operator, fixed nodes having a unit diagonal
+struct Level {
+    ny:usize,
+    nx:usize,
+    nz:usize,
+    gx:Vec<Real>,
+    gy:Vec<Real>,
+    gz:Vec<Real>,
+    diag:Vec<Real>,
+    free:Vec<bool>
+}
+
+impl Level {
+    fn new(mesh:&Mesh)->Self {
+	let n = mesh.len();
+	let free : Vec<bool> = mesh.fixed.iter().map(|f| f.is_none()).collect();
+	let mut diag = vec![1.0;n];
+	for i in 0..n {
+	    if free[i] {
+		diag[i] = mesh.neighbours(i).map(|(_,g)| g).sum();
+	    }
+	}
+	let (nx,nz) = (mesh.nx,mesh.nz);
+	let decouple = |g:&[Real],step:usize| -> Vec<Real> {
+	    (0..n).map(|i| if free[i] && i + step < n && free[i + step] { g[i] } else { 0.0 })
+		.collect()
+	};
+	Self {
+	    ny:mesh.ny,
+	    nx,
+	    nz,
+	    gx:decouple(&mesh.gx,nz),
+	    gy:decouple(&mesh.gy,nx * nz),
+	    gz:decouple(&mesh.gz,1),
+	    diag,
+	    free
+	}
+    }
+
+    fn len(&self)->usize {
+	self.ny * self.nx * self.nz
+    }
+
+    fn idx(&self,y:usize,x:usize,z:usize)->usize {
+	(y * self.nx + x) * self.nz + z
+    }
+
+    /// Sum of the conductance-weighted values of the neighbours of
+    /// node i in the same plane
+    fn lateral(&self,u:&[Real],i:usize,y:usize,x:usize)->Real {
+	let (nz,sy) = (self.nz,self.nx * self.nz);
+	let mut s = 0.0;
+	if x > 0 { s += self.gx[i - nz] * u[i - nz]; }
+	if x + 1 < self.nx { s += self.gx[i] * u[i + nz]; }
+	if y > 0 { s += self.gy[i - sy] * u[i - sy]; }
+	if y + 1 < self.ny { s += self.gy[i] * u[i + sy]; }
+	s
+    }
+
+    fn apply(&self,u:&[Real],out:&mut [Real]) {
+	let (nx,nz) = (self.nx,self.nz);
+	out.par_chunks_mut(nx * nz).enumerate().for_each(|(y,row)| {
+	    for x in 0..nx {
+		for z in 0..nz {
+		    let i = self.idx(y,x,z);
+		    let mut s = self.diag[i] * u[i] - self.lateral(u,i,y,x);
+		    if z > 0 { s -= self.gz[i - 1] * u[i - 1]; }
+		    if z + 1 < nz { s -= self.gz[i] * u[i + 1]; }
+		    row[x * nz + z] = s;
+		}
+	    }
+	});
+    }
+
+    /// Gauss-Seidel relaxation solving each vertical column exactly,
+    /// on the columns of each colour of a checkerboard in turn
+    fn smooth(&self,u:&mut [Real],b:&[Real],colours:[usize;2]) {
+	let (nx,nz) = (self.nx,self.nz);
+	let mut tmp = vec![0.0;u.len()];
+	for colour in colours {
+	    {
+		let u : &[Real] = u;
+		tmp.par_chunks_mut(nx * nz).enumerate().for_each(|(y,row)| {
+		    let mut cp = vec![0.0;nz];
+		    let mut dp = vec![0.0;nz];
+		    for x in (0..nx).filter(|x| (x + y) % 2 == colour) {
+			let i0 = self.idx(y,x,0);
+			for z in 0..nz {
+			    let i = i0 + z;
+			    let r = b[i] + self.lateral(u,i,y,x);
+			    let (a,cp0,dp0) = if z > 0 {
+				(-self.gz[i - 1],cp[z - 1],dp[z - 1])
+			    } else {
+				(0.0,0.0,0.0)
+			    };
+			    let c = if z + 1 < nz { -self.gz[i] } else { 0.0 };
+			    let m = self.diag[i] - a * cp0;
+			    cp[z] = c / m;
+			    dp[z] = (r - a * dp0) / m;
+			}
+			let out = &mut row[x * nz..(x + 1) * nz];
+			out[nz - 1] = dp[nz - 1];
+			for z in (0..nz - 1).rev() {
+			    out[z] = dp[z] - cp[z] * out[z + 1];
+			}
+		    }
+		});
+	    }
+	    u.par_chunks_mut(nx * nz).zip(tmp.par_chunks(nx * nz)).enumerate()
+		.for_each(|(y,(ur,tr))| {
+		    for x in (0..nx).filter(|x| (x + y) % 2 == colour) {
+			ur[x * nz..(x + 1) * nz].copy_from_slice(&tr[x * nz..(x + 1) * nz]);
+		    }
+		});
+	}
+    }
+
+    /// Galerkin operator of the aggregation of 2 x 2 columns
+    fn coarsen(&self)->Self {
+	let (ny,nx,nz) = (self.ny,self.nx,self.nz);
+	let (cy,cx) = (ny.div_ceil(2),nx.div_ceil(2));
+	let n = cy * cx * nz;
+	let mut c = Self {
+	    ny:cy,
+	    nx:cx,
+	    nz,
+	    gx:vec![0.0;n],
+	    gy:vec![0.0;n],
+	    gz:vec![0.0;n],
+	    diag:vec![0.0;n],
+	    free:vec![false;n]
+	};
+	for y in 0..ny {
+	    for x in 0..nx {
+		for z in 0..nz {
+		    let i = self.idx(y,x,z);
+		    if !self.free[i] {
+			continue;
+		    }
+		    let j = c.idx(y / 2,x / 2,z);
+		    c.free[j] = true;
+		    c.diag[j] += self.diag[i];
+		    // Couplings inside an aggregate cancel out
+		    if x % 2 == 0 {
+			c.diag[j] -= 2.0 * self.gx[i];
+		    } else {
+			c.gx[j] += self.gx[i];
+		    }
+		    if y % 2 == 0 {
+			c.diag[j] -= 2.0 * self.gy[i];
+		    } else {
+			c.gy[j] += self.gy[i];
+		    }
+		    c.gz[j] += self.gz[i];
+		}
+	    }
+	}
+	for j in 0..n {
+	    if !c.free[j] {
+		c.diag[j] = 1.0;
+	    }
+	}
+	c
+    }
+
+    fn restrict(&self,coarse:&Self,r:&[Real])->Vec<Real> {
+	let mut rc = vec![0.0;coarse.len()];
+	for y in 0..self.ny {
+	    for x in 0..self.nx {
+		for z in 0..self.nz {
+		    let i = self.idx(y,x,z);
+		    if self.free[i] {
+			rc[coarse.idx(y / 2,x / 2,z)] += r[i];
+		    }
+		}
+	    }
+	}
+	rc
+    }
+
+    fn prolong(&self,coarse:&Self,uc:&[Real],u:&mut [Real]) {
+	for y in 0..self.ny {
+	    for x in 0..self.nx {
+		for z in 0..self.nz {
+		    let i = self.idx(y,x,z);
+		    if self.free[i] {
+			u[i] += uc[coarse.idx(y / 2,x / 2,z)];
+		    }
+		}
+	    }
+	}
+    }
+}
+
+/// Symmetric multigrid V-cycle approximately solving A u = b
+fn vcycle(levels:&[Level],b:&[Real])->Vec<Real> {
+    let l = &levels[0];
+    let mut u = vec![0.0;b.len()];
+    if levels.len() == 1 {
+	for _ in 0..COARSEST_SWEEPS {
+	    l.smooth(&mut u,b,[0,1]);
+	    l.smooth(&mut u,b,[1,0]);
+	}
+	return u;
+    }
+    l.smooth(&mut u,b,[0,1]);
+    let mut r = vec![0.0;b.len()];
+    l.apply(&u,&mut r);
+    r.par_iter_mut().zip(b.par_iter()).for_each(|(r,&b)| *r = b - *r);
+    let rc = l.restrict(&levels[1],&r);
+    let uc = vcycle(&levels[1..],&rc);
+    l.prolong(&levels[1],&uc,&mut u);
+    l.smooth(&mut u,b,[1,0]);
+    u
+}
+
+fn dot(a:&[Real],b:&[Real])->Real {
+    a.par_iter().zip(b.par_iter()).map(|(x,y)| x * y).sum()
+}
+
+/// Conjugate gradient preconditioned by multigrid V-cycles; returns
+/// the solution, the number of iterations and the relative residual
+fn pcg(levels:&[Level],b:&[Real],tolerance:Real,max_iterations:usize)
+       ->(Vec<Real>,usize,Real) {
+    let l = &levels[0];
+    let bn = sqrt(dot(b,b));
+    let mut x = vec![0.0;b.len()];
+    if bn == 0.0 {
+	return (x,0,0.0);
+    }
+    let mut r = b.to_vec();
+    let mut z = vcycle(levels,&r);
+    let mut p = z.clone();
+    let mut rz = dot(&r,&z);
+    let mut q = vec![0.0;b.len()];
+    let mut res = 1.0;
+    for it in 0..max_iterations {
+	l.apply(&p,&mut q);
+	let alpha = rz / dot(&p,&q);
+	x.par_iter_mut().zip(p.par_iter()).for_each(|(x,&p)| *x += alpha * p);
+	r.par_iter_mut().zip(q.par_iter()).for_each(|(r,&q)| *r -= alpha * q);
+	res = sqrt(dot(&r,&r)) / bn;
+	if res <= tolerance {
+	    return (x,it + 1,res);
+	}
+	z = vcycle(levels,&r);
+	let rz_new = dot(&r,&z);
+	let beta = rz_new / rz;
+	rz = rz_new;
+	p.par_iter_mut().zip(z.par_iter()).for_each(|(p,&z)| *p = z + beta * *p);
+    }
+    (x,max_iterations,res)
+}
+
+/// Thicknesses of air cells filling margin millimeters, growing away
+/// from the board from about first
+fn air_cells(margin:Real,first:Real)->Vec<Real> {
+    let mut cells = Vec::new();
+    let mut total = 0.0;
+    let mut dz = first.min(margin);
+    while total < margin || cells.is_empty() {
+	cells.push(dz);
+	total += dz;
+	dz *= AIR_GROWTH;
+    }
+    cells.iter().map(|dz| dz * margin / total).collect()
+}
+
+/// Crops the conductors, given per pixel of each layer, to the
+/// bounding box of the copper of conductors 0..n widened by margin
+/// pixels
+pub fn crop(conductors:&[Array2<Option<usize>>],n:usize,margin:usize)
+	    ->Res<Vec<Array2<Option<usize>>>> {
+    let mut bbox : Option<(usize,usize,usize,usize)> = None;
+    for cond in conductors.iter() {
+	for ((y,x),&k) in cond.indexed_iter() {
+	    if k.is_some_and(|k| k < n) {
+		let b = bbox.get_or_insert((y,y,x,x));
+		*b = (b.0.min(y),b.1.max(y),b.2.min(x),b.3.max(x));
+	    }
+	}
+    }
+    let (y0,y1,x0,x1) = bbox.ok_or_else(|| error("The selected nets have no copper"))?;
+    let (ny,nx) = conductors[0].dim();
+    let (y0,y1) = (y0.saturating_sub(margin),(y1 + margin + 1).min(ny));
+    let (x0,x1) = (x0.saturating_sub(margin),(x1 + margin + 1).min(nx));
+    info!("Cropping the field solver input to rows {}..{}, columns {}..{}",
+	  y0,y1,x0,x1);
+    Ok(conductors.iter().map(|c| c.slice(s![y0..y1,x0..x1]).to_owned()).collect())
+}
+
+/// Voxelizes the copper planes with the dielectrics of the stackup
+/// and air above and below, on cells of f x f pixels of size delta
+/// millimeters.  Air cells grow thicker away from the board.
+/// Conductors are given per pixel of each layer; conductor n stands
+/// for other copper and the ground at the top and bottom of the box.
+fn mesh(config:&Config,fs:&FieldSolver,delta:Real,f:usize,
+	conductors:&[Array2<Option<usize>>],n:usize)->Res<Mesh> {
+    let nlay = conductors.len();
+    let (ny0,nx0) = conductors[0].dim();
+    let (ny,nx) = (ny0.div_ceil(f),nx0.div_ceil(f));
+    let cell = f as Real * delta;
+
+    // Thickness and permittivity of the slabs between planes
+    let z_cells = fs.z_cells.max(1);
+    let dz = |ilay:usize| config.dielectric(ilay).thickness / z_cells as Real;
+    let (top,bottom) = if nlay > 1 { (dz(0),dz(nlay - 2)) } else { (cell,cell) };
+    let mut slabs : Vec<(Real,Real)> = air_cells(fs.margin,top).iter().rev()
+	.map(|&dz| (dz,1.0))
+	.collect();
+    let mut planes = Vec::new();
+    for ilay in 0..nlay {
+	planes.push(slabs.len());
+	if ilay + 1 < nlay {
+	    let d = config.dielectric(ilay);
+	    for _ in 0..z_cells {
+		slabs.push((d.thickness / z_cells as Real,d.eps_rel));
+	    }
+	}
+    }
+    slabs.extend(air_cells(fs.margin,bottom).iter().map(|&dz| (dz,1.0)));
+    let nz = slabs.len() + 1;
+    if ny * nx * nz > fs.max_nodes {
+	return Err(error(&format!(
+	    "The field solver grid would have {} x {} x {} nodes, more than \
+	     max_nodes ({}); increase cell or narrow the region of interest",
+	    ny,nx,nz,fs.max_nodes)));
+    }
+
+    // Conductances per plane, in farads from lengths in millimeters
+    let lateral : Vec<Real> = (0..nz)
+	.map(|p| {
+	    let above = if p > 0 { slabs[p - 1].0 * slabs[p - 1].1 } else { 0.0 };
+	    let below = if p < nz - 1 { slabs[p].0 * slabs[p].1 } else { 0.0 };
+	    EPS0 * 1e-3 * 0.5 * (above + below)
+	})
+	.collect();
+    let vertical : Vec<Real> = slabs.iter()
+	.map(|&(dz,eps)| EPS0 * 1e-3 * eps * cell * cell / dz)
+	.chain(std::iter::once(0.0))
+	.collect();
+
+    let len = ny * nx * nz;
+    let mut m = Mesh {
+	ny,
+	nx,
+	nz,
+	gx:vec![0.0;len],
+	gy:vec![0.0;len],
+	gz:vec![0.0;len],
+	fixed:vec![None;len]
+    };
+    for y in 0..ny {
+	for x in 0..nx {
+	    for z in 0..nz {
+		let i = (y * nx + x) * nz + z;
+		if x + 1 < nx { m.gx[i] = lateral[z]; }
+		if y + 1 < ny { m.gy[i] = lateral[z]; }
+		m.gz[i] = vertical[z];
+	    }
+	    m.fixed[(y * nx + x) * nz] = Some(n);
+	    m.fixed[(y * nx + x) * nz + nz - 1] = Some(n);
+	}
+    }
+
+    // Each cell takes the conductor covering most of its pixels, if
+    // copper covers at least half of them
+    let mut counts = vec![0;n + 1];
+    for (ilay,cond) in conductors.iter().enumerate() {
+	let z = planes[ilay];
+	for y in 0..ny {
+	    for x in 0..nx {
+		counts.iter_mut().for_each(|c| *c = 0);
+		for py in y * f..(y * f + f).min(ny0) {
+		    for px in x * f..(x * f + f).min(nx0) {
+			if let Some(k) = cond[[py,px]] {
+			    counts[k] += 1;
+			}
+		    }
+		}
+		let copper : usize = counts.iter().sum();
+		if 2 * copper >= f * f && copper > 0 {
+		    let (k,_) = counts.iter().enumerate()
+			.max_by_key(|&(k,&c)| (c,std::cmp::Reverse(k)))
+			.unwrap();
+		    m.fixed[(y * nx + x) * nz + z] = Some(k);
+		}
+	    }
+	}
+    }
+    Ok(m)
+}
+
+/// Maxwell capacitance matrix, in farads, of conductors 0..n given
+/// per pixel of each layer, by solving Laplace's equation with each
+/// conductor in turn at 1 V and all other copper at 0 V.  Copper of
+/// conductor n is held at 0 V.
+pub fn solve(config:&Config,fs:&FieldSolver,delta:Real,
+	     conductors:&[Array2<Option<usize>>],n:usize)->Res<Array2<Real>> {
+    if conductors.is_empty() {
+	return Err(error("No layers for the field solver"));
+    }
+    let f = fs.cell.map(|c| (c / delta).round().max(1.0) as usize).unwrap_or(1);
+    let m = mesh(config,fs,delta,f,conductors,n)?;
+    let mut levels = vec![Level::new(&m)];
+    loop {
+	let l = levels.last().unwrap();
+	if l.ny * l.nx <= COARSEST_COLUMNS || (l.ny == 1 && l.nx == 1) {
+	    break;
+	}
+	let c = l.coarsen();
+	levels.push(c);
+    }
+    info!("Field solver grid: {} x {} x {} nodes of {:.4} mm, {} multigrid levels",
+	  m.ny,m.nx,m.nz,f as Real * delta,levels.len());
+
+    let mut members : Vec<Vec<usize>> = vec![Vec::new();n];
+    for (i,f) in m.fixed.iter().enumerate() {
+	if let &Some(k) = f {
+	    if k < n {
+		members[k].push(i);
+	    }
+	}
+    }
+
+    let mut c = Array2::zeros((n,n));
+    for k in 0..n {
+	if members[k].is_empty() {
+	    warn!("Conductor {} has no copper on the solver grid",k);
+	    continue;
+	}
+	let mut b = vec![0.0;m.len()];
+	for &i in members[k].iter() {
+	    for (j,g) in m.neighbours(i) {
+		if m.fixed[j].is_none() {
+		    b[j] += g;
+		}
+	    }
+	}
+	let (u,iterations,res) = pcg(&levels,&b,fs.tolerance,fs.max_iterations);
+	if res > fs.tolerance {
+	    warn!("Conductor {}: no convergence after {} iterations, residual {:.2e}",
+		  k,iterations,res);
+	} else {
+	    info!("Conductor {}: converged in {} iterations",k,iterations);
+	}
+	let potential = |i:usize| match m.fixed[i] {
+	    None => u[i],
+	    Some(j) => if j == k { 1.0 } else { 0.0 }
+	};
+	for j in 0..n {
+	    c[[j,k]] = members[j].iter()
+		.map(|&i| m.neighbours(i)
+		     .map(|(l,g)| g * (potential(i) - potential(l)))
+		     .sum::<Real>())
+		.sum();
+	}
+    }
+    Ok(0.5 * (&c + &c.t()))
+}
+
+#[cfg(test)]
+#[test]
+fn test_plates() {
+    // Two 8 mm square plates 0.05 mm apart: their mutual capacitance
+    // is close to the parallel plate value, and both are alike
+    let config : Config = ron::from_str(
+	"(input:\"\",output:\"\",layers:[],roi:None,mark:None,\
+	  origin:(x:0.0,y:0.0),dpi:254.0,eps_rel:4.0,thickness:0.05,cap_min:0.0)").unwrap();
+    let fs = FieldSolver { lateral_margin:0.5,..FieldSolver::default() };
+    let delta = 0.1;
+    let plate = |k:usize| Array2::from_shape_fn((120,120),|(y,x)| {
+	((20..100).contains(&y) && (20..100).contains(&x)).then_some(k)
+    });
+    let conductors = crop(&[plate(0),plate(1)],2,5).unwrap();
+    assert_eq!(conductors[0].dim(),(90,90));
+    let c = solve(&config,&fs,delta,&conductors,2).unwrap();
+    let c0 = EPS0 * 4.0 * 8e-3 * 8e-3 / 0.05e-3;
+    assert!(c[[0,1]] < 0.0 && (-c[[0,1]] / c0 - 1.0).abs() < 0.05);
+    assert!((c[[0,0]] / c[[1,1]] - 1.0).abs() < 1e-3);
+    assert_eq!(c[[0,1]],c[[1,0]]);
+
+    let small = FieldSolver { max_nodes:1000,..fs };
+    assert!(solve(&config,&small,delta,&conductors,2).is_err());
+    assert!(crop(&[plate(2)],2,5).is_err());
+}
@@ -272,12 +272,16 @@ impl From<&str> for InterpolationMode {
 }
 
 pub struct NetInfos {
-    pub index:BTreeMap<String,Vec<Point>>
+    pub index:BTreeMap<String,Vec<Point>>,
+    /// Rank in the file of each flash point of index
+    pub ranks:BTreeMap<String,Vec<usize>>
 }
 
 impl From<&Image> for NetInfos {
     fn from(img:&Image)->Self {
 	let mut index : BTreeMap<String,Vec<Point>> = BTreeMap::new();
+	let mut ranks : BTreeMap<String,Vec<usize>> = BTreeMap::new();
+	let mut rank = 0;
 	let mut net : Option<&str> = None;
 	let mut _scale_x = 1.0;
 	let mut _scale_y = 1.0;
@@ -327,12 +331,14 @@ impl From<&Image> for NetInfos {
 			let x = x_cf.convert(x);
 			let y = y_cf.convert(y);
 			v.push(Point{ x, y });
+			ranks.entry(name.to_string()).or_default().push(rank);
+			rank += 1;
 		    }
 		}
 		_ => ()
 	    }
 	}
-	Self { index }
+	Self { index, ranks }
     }
 }
 
@@ -0,0 +1,147 @@
+use crate::{
+    common::*,
+    math::*,
+    config::{Point,Rectangle}
+};
+
+/// Mapping between bitmap pixels and Gerber coordinates, in
+/// millimeters.  The origin is the Gerber position of the
+/// bottom-left corner of the bitmap; row 0 is the top row.
+#[derive(Clone,Debug)]
+pub struct Grid {
+    pub ny:usize,
+    pub nx:usize,
+    pub x0:Real,
+    pub y0:Real,
+    pub delta:Real
+}
+
+/// Rectangular range of pixels of a parent grid
+#[derive(Clone,Debug)]
+pub struct Window {
+    pub iy0:usize,
+    pub iy1:usize,
+    pub ix0:usize,
+    pub ix1:usize,
+    ny:usize,
+    nx:usize
+}
+
+impl Window {
+    pub fn dim(&self)->(usize,usize) {
+	(self.iy1 - self.iy0,self.ix1 - self.ix0)
+    }
+
+    /// True if the pixel (in window coordinates) lies on an edge
+    /// of the window that cuts through the parent grid
+    pub fn on_cut_edge(&self,iy:usize,ix:usize)->bool {
+	let (ny,nx) = self.dim();
+	(iy == 0 && self.iy0 > 0) ||
+	    (iy + 1 == ny && self.iy1 < self.ny) ||
+	    (ix == 0 && self.ix0 > 0) ||
+	    (ix + 1 == nx && self.ix1 < self.nx)
+    }
+}
+
+impl Grid {
+    pub fn new(ny:usize,nx:usize,origin:&Point,dpi:Real)->Self {
+	Self {
+	    ny,
+	    nx,
+	    x0:origin.x,
+	    y0:origin.y,
+	    delta:25.4 / dpi
+	}
+    }
+
+    /// Area of one pixel in square meters
+    pub fn pixel_area(&self)->Real {
+	self.delta * self.delta * 1e-6
+    }
+
+    /// Fractional pixel indices (iy,ix) of a Gerber point; integer
+    /// values correspond to pixel centers
+    pub fn index(&self,x:Real,y:Real)->(Real,Real) {
+	((self.ny as Real - (y - self.y0)/self.delta - 0.5),
+	 (x - self.x0)/self.delta - 0.5)
+    }
+
+    /// Pixel whose square contains the given Gerber point, if any.
+    /// Points on an edge between pixels go to the pixel right of or
+    /// below it.
+    pub fn pixel(&self,x:Real,y:Real)->Option<(usize,usize)> {
+	let (iyf,ixf) = self.index(x,y);
+	let iyf = (iyf + 0.5).floor();
+	let ixf = (ixf + 0.5).floor();
+	if 0.0 <= iyf && 0.0 <= ixf {
+	    let iy = iyf as usize;
+	    let ix = ixf as usize;
+	    if iy < self.ny && ix < self.nx {
+		return Some((iy,ix));
+	    }
+	}
+	None
+    }
+
+    /// Gerber coordinates of the center of a pixel
+    pub fn point(&self,iy:usize,ix:usize)->(Real,Real) {
+	(self.x0 + self.delta * (ix as Real + 0.5),
+	 self.y0 + self.delta * ((self.ny - iy) as Real - 0.5))
+    }
+
+    /// Pixels covering the given rectangle, clipped to the grid
+    pub fn window(&self,r:&Rectangle)->Res<Window> {
+	let x_min = r.p0.x.min(r.p1.x);
+	let x_max = r.p0.x.max(r.p1.x);
+	let y_min = r.p0.y.min(r.p1.y);
+	let y_max = r.p0.y.max(r.p1.y);
+	let clip = |u:Real,n:usize| u.max(0.0).min(n as Real) as usize;
+	let ix0 = clip(((x_min - self.x0)/self.delta).floor(),self.nx);
+	let ix1 = clip(((x_max - self.x0)/self.delta).ceil(),self.nx);
+	let iy0 = clip((self.ny as Real - (y_max - self.y0)/self.delta).floor(),
+		       self.ny);
+	let iy1 = clip((self.ny as Real - (y_min - self.y0)/self.delta).ceil(),
+		       self.ny);
+	if ix0 >= ix1 || iy0 >= iy1 {
+	    return Err(error(&format!(
+		"Rectangle ({},{})-({},{}) does not intersect the bitmaps",
+		x_min,y_min,x_max,y_max)));
+	}
+	Ok(Window { iy0,iy1,ix0,ix1,ny:self.ny,nx:self.nx })
+    }
+
+    /// Grid of the pixels of a window
+    pub fn sub(&self,w:&Window)->Self {
+	let (ny,nx) = w.dim();
+	Self {
+	    ny,
+	    nx,
+	    x0:self.x0 + self.delta * w.ix0 as Real,
+	    y0:self.y0 + self.delta * (self.ny - w.iy1) as Real,
+	    delta:self.delta
+	}
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_pixel() {
+    let grid = Grid::new(4,3,&Point { x:1.0,y:2.0 },101.6);
+    let d = grid.delta;
+    // Center, and corners inside the bottom-left pixel
+    assert_eq!(grid.index(1.0 + 0.5*d,2.0 + 0.5*d),(3.0,0.0));
+    assert_eq!(grid.pixel(1.0 + 0.5*d,2.0 + 0.5*d),Some((3,0)));
+    assert_eq!(grid.pixel(1.0 + 0.01*d,2.0 + 0.99*d),Some((3,0)));
+    assert_eq!(grid.pixel(1.0 + 0.99*d,2.0 + 0.01*d),Some((3,0)));
+    // Edges between pixels and edges of the grid
+    assert_eq!(grid.pixel(1.0 + d,2.0 + 2.0*d),Some((2,1)));
+    assert_eq!(grid.pixel(1.0,2.0 + 3.5*d),Some((0,0)));
+    assert_eq!(grid.pixel(1.0 + 3.0*d,2.0 + d),None);
+    assert_eq!(grid.pixel(1.0 + d,2.0 + 4.0*d),Some((0,1)));
+    assert_eq!(grid.pixel(1.0 + d,2.0),None);
+    assert_eq!(grid.pixel(1.0 - 1e-9,2.0 + d),None);
+    for (iy,ix) in [(0,0),(1,2),(3,1)] {
+	let (x,y) = grid.point(iy,ix);
+	assert_eq!(grid.pixel(x,y),Some((iy,ix)));
+    }
+}
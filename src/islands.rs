@@ -0,0 +1,148 @@
+use log::info;
+use std::{
+    fs::File,
+    io::{Write,BufWriter}
+};
+
+use crate::{
+    common::*,
+    math::*,
+    config::Config,
+    components::ComponentStats,
+    grid::Grid,
+    ndarray_image
+};
+
+/// Margin around thumbnails, in pixels
+const THUMBNAIL_MARGIN : usize = 8;
+
+/// Largest side of thumbnails, in pixels; larger crops are subsampled
+const THUMBNAIL_SIZE_MAX : usize = 512;
+
+/// Area and inclusive bounding box (iy,ix) of a component, in pixels
+#[derive(Clone,Debug)]
+pub struct Extent {
+    pub area:usize,
+    pub min:(usize,usize),
+    pub max:(usize,usize)
+}
+
+impl Extent {
+    pub fn merge(&mut self,other:&Extent) {
+	self.area += other.area;
+	self.min = (self.min.0.min(other.min.0),self.min.1.min(other.min.1));
+	self.max = (self.max.0.max(other.max.0),self.max.1.max(other.max.1));
+    }
+}
+
+impl From<&ComponentStats> for Extent {
+    fn from(st:&ComponentStats)->Self {
+	Self { area:st.area,min:st.min.into(),max:st.max.into() }
+    }
+}
+
+fn thumbnail(labels:&Array2<u32>,icom:usize,e:&Extent)->Array2<u8> {
+    let (ny,nx) = labels.dim();
+    let iy0 = e.min.0.saturating_sub(THUMBNAIL_MARGIN);
+    let ix0 = e.min.1.saturating_sub(THUMBNAIL_MARGIN);
+    let iy1 = (e.max.0 + THUMBNAIL_MARGIN + 1).min(ny);
+    let ix1 = (e.max.1 + THUMBNAIL_MARGIN + 1).min(nx);
+    let step = (iy1 - iy0).max(ix1 - ix0).div_ceil(THUMBNAIL_SIZE_MAX);
+    let label = icom as u32 + 1;
+    Array2::from_shape_fn(((iy1 - iy0).div_ceil(step),(ix1 - ix0).div_ceil(step)),
+			  |(jy,jx)| {
+			      match labels[[iy0 + jy*step,ix0 + jx*step]] {
+				  0 => 0,
+				  l if l == label => 255,
+				  _ => 96
+			      }
+			  })
+}
+
+/// Writes the report of the unnamed components of a layer, with
+/// thumbnails of the largest ones if labels are given, and names
+/// those large enough to be floating conductors
+pub fn unnamed(config:&Config,ilay:usize,names:&mut [Option<String>],
+	       extents:&[Extent],grid:&Grid,labels:Option<&Array2<u32>>)
+	       ->Res<()> {
+    let lname = &config.layers[ilay].name;
+    let mut unnamed : Vec<usize> = (0..names.len())
+	.filter(|&icom| names[icom].is_none())
+	.collect();
+    unnamed.sort_by_key(|&icom| std::cmp::Reverse(extents[icom].area));
+    let pixel_area = grid.delta * grid.delta;
+    let base = format!("{}/unnamed-{}-{}",config.output,ilay,lname);
+
+    let path = format!("{}.txt",base);
+    info!("Layer {} ({}): {} unnamed components, see {}",
+	  ilay,lname,unnamed.len(),path);
+    let fd = File::create(path)?;
+    let mut fd = BufWriter::new(fd);
+    writeln!(fd,"# Component, area in mm^2, bounding box (x0,y0)-(x1,y1) in mm")?;
+    for (rank,&icom) in unnamed.iter().enumerate() {
+	let e = &extents[icom];
+	let area = e.area as Real * pixel_area;
+	let (x0,y1) = grid.point(e.min.0,e.min.1);
+	let (x1,y0) = grid.point(e.max.0,e.max.1);
+	let h = 0.5 * grid.delta;
+	write!(fd,"{} {:.4} ({:.3},{:.3})-({:.3},{:.3})",
+	       icom + 1,area,x0 - h,y0 - h,x1 + h,y1 + h)?;
+	if config.islands.floating_area.map(|a| area >= a).unwrap_or(false) {
+	    let name = format!("FLOAT-{}-{}",lname,icom + 1);
+	    write!(fd," {}",name)?;
+	    names[icom] = Some(name);
+	}
+	writeln!(fd)?;
+	if let Some(labels) = labels {
+	    if rank < config.islands.thumbnails {
+		std::fs::create_dir_all(&base)?;
+		let img = thumbnail(labels,icom,e);
+		ndarray_image::save_gray_image(format!("{}/{}.png",base,icom + 1),
+					       img.view())?;
+	    }
+	}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_unnamed() {
+    let dir = std::env::temp_dir().join(format!("capest-islands-{}",std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config : Config = ron::from_str(&format!(
+	"(input:\"\",output:{:?},layers:[(name:\"L\",bitmap:\"\",gerber:\"\")],\
+	  roi:None,mark:None,origin:(x:0.0,y:0.0),dpi:254.0,eps_rel:4.0,\
+	  thickness:0.1,cap_min:0.0,islands:(floating_area:Some(0.1),thumbnails:1))",
+	dir)).unwrap();
+    // A 4 by 5 island, a 1 by 2 one and a named component
+    let mut labels = Array2::zeros((12,16));
+    labels.slice_mut(s![2..6,3..8]).fill(1);
+    labels.slice_mut(s![9..10,10..12]).fill(2);
+    labels.slice_mut(s![10..12,0..3]).fill(3);
+    let extents = vec![
+	Extent { area:20,min:(2,3),max:(5,7) },
+	Extent { area:2,min:(9,10),max:(9,11) },
+	Extent { area:6,min:(10,0),max:(11,2) }
+    ];
+    let mut names = vec![None,None,Some("N".to_string())];
+    let grid = Grid::new(12,16,&crate::config::Point { x:0.0,y:0.0 },254.0);
+    unnamed(&config,0,&mut names,&extents,&grid,Some(&labels)).unwrap();
+
+    assert_eq!(names,vec![Some("FLOAT-L-1".to_string()),None,Some("N".to_string())]);
+    let report = std::fs::read_to_string(dir.join("unnamed-0-L.txt")).unwrap();
+    assert_eq!(report.lines().skip(1).collect::<Vec<_>>(),
+	       vec!["1 0.2000 (0.300,0.600)-(0.800,1.000) FLOAT-L-1",
+		    "2 0.0200 (1.000,0.200)-(1.200,0.300)"]);
+    assert!(dir.join("unnamed-0-L/1.png").exists());
+    assert!(!dir.join("unnamed-0-L/2.png").exists());
+
+    // Thumbnails are cropped to the margin around the component and
+    // subsampled beyond the largest size
+    let t = thumbnail(&labels,1,&extents[1]);
+    assert_eq!(t.dim(),(11,14));
+    assert_eq!((t[[8,8]],t[[8,7]],t[[1,1]],t[[0,0]]),(255,0,96,0));
+    let long = Array2::from_elem((20,1100),1);
+    let t = thumbnail(&long,0,&Extent { area:1100,min:(0,0),max:(0,1099) });
+    assert_eq!(t.dim(),(3,367));
+}
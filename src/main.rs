@@ -12,8 +12,28 @@ mod progress;
 mod ndarray_image;
 mod gerber;
 mod common;
+mod grid;
+mod binarize;
+mod registration;
+mod alignment;
+mod components;
+mod bands;
+mod fax;
+mod bitmap;
+mod morphology;
+mod drill;
+mod shorts;
+mod islands;
+mod opens;
+mod matching;
+mod classes;
+mod coplanar;
+mod maxwell;
+mod field;
+mod random_walk;
+mod section;
 
-use log::{trace,info,error};
+use log::{trace,info,warn,error};
 use std::collections::{BTreeSet,BTreeMap};
 use std::error::Error;
 use std::fs::File;
@@ -24,7 +44,12 @@ use pico_args::Arguments;
 
 use xorwow::Xorwow;
 use gerber::{Image,NetInfos};
-use config::{Config,Loadable};
+use config::{Config,Loadable,Connectivity};
+use grid::{Grid,Window};
+use components::{ConnectedComponents,Overlap};
+use classes::NetClasses;
+use maxwell::Maxwell;
+use section::Section;
 
 use common::*;
 
@@ -33,146 +58,134 @@ fn convert(x:Real)->u8 {
     x.max(0).min(255) as u8
 }
 
+/// Number of bits of the per-pixel layer mask
+const LAYERS_MAX : usize = 16;
+
 struct Artwork {
     num_layers:usize,
-    layers:Array2<u16>
+    layers:Array2<u16>,
+    /// Copper coverage of the layers using it, out of 255
+    coverage:Vec<Option<Array2<u8>>>
 }
 
 impl Artwork {
-    pub fn new<P:AsRef<Path>>(lay_fns:&[P])->Res<Self> {
-	let mut layers_opt = None;
-	for (ilay,lay_fn) in lay_fns.iter().enumerate() {
+    /// Loads the layer bitmaps onto a common grid of the given
+    /// origin and resolution.  The grid has the dimensions of the
+    /// first bitmap without its own origin and dpi, or else covers
+    /// the first bitmap; other bitmaps are resampled onto it.
+    pub fn new(input:&str,lays:&[config::Layer],
+	       origin:&config::Point,dpi:Real)->Res<Self> {
+	if lays.len() > LAYERS_MAX {
+	    return Err(error(&format!(
+		"Too many layers: {}, at most {} are supported",
+		lays.len(),LAYERS_MAX)));
+	}
+	// Coverage of each layer, out of 255; without coverage, pixels
+	// have either none or full copper
+	let mut coppers = Vec::new();
+	for lay in lays.iter() {
+	    let lay_fn = format!("{}/{}",input,lay.bitmap);
 	    // info!("Loading layer {} from {:?}",ilay,lay_fn);
-	    let img = ndarray_image::open_gray_image(lay_fn)?;
-	    let (ny,nx) = img.dim();
-	    let mut layers = layers_opt.take()
-		.unwrap_or_else(|| Array2::zeros((ny,nx)));
-	    let (nyp,nxp) = layers.dim();
-	    if ny != nyp || nx != nxp {
-		return Err(error(&format!(
-		    "Incoherent dimensions: ({},{}) vs ({},{})",
-		    nyp,nxp,ny,nx)));
+	    let img = bitmap::open(&lay_fn)?;
+	    let img = binarize::levels(&img,&lay.binarization);
+	    let threshold = lay.binarization.threshold;
+	    binarize::check(&lay.name,&binarize::histogram(&img),threshold);
+	    let coverage = lay.binarization.coverage;
+	    coppers.push(img.mapv(|l| {
+		if l <= threshold { 0 } else if coverage { l } else { 255 }
+	    }));
+	}
+	let num_layers = lays.len();
+	if num_layers == 0 {
+	    return Err(error("No layers"));
+	}
+	let (ny,nx) = match lays.iter().position(|l| !l.has_own_geometry()) {
+	    Some(ilay) => coppers[ilay].dim(),
+	    None => {
+		let (o,r) = lays[0].geometry(origin,dpi);
+		bitmap::covering_dim(coppers[0].dim(),&o,&r,origin,25.4 / dpi)
 	    }
+	};
+	components::check_dim(ny,nx)?;
+	let grid = Grid::new(ny,nx,origin,dpi);
+	let mut layers = Array2::zeros((ny,nx));
+	let mut coverage = Vec::new();
+	for (ilay,(lay,copper)) in lays.iter().zip(coppers).enumerate() {
+	    let copper = if lay.has_own_geometry() {
+		let (o,r) = lay.geometry(origin,dpi);
+		info!("Resampling layer {} ({}) from {} x {} dpi",
+		      ilay,lay.name,r.x,r.y);
+		bitmap::resample(&copper,&o,&r,&grid)
+	    } else {
+		let (nyp,nxp) = copper.dim();
+		if ny != nyp || nx != nxp {
+		    return Err(error(&format!(
+			"Incoherent dimensions: ({},{}) vs ({},{})",
+			ny,nx,nyp,nxp)));
+		}
+		copper
+	    };
 	    let mask : u16 = 1 << ilay;
-	    for iy in 0..ny {
-		for ix in 0..nx {
-		    let l = img[[iy,ix]];
-		    layers[[iy,ix]] |= if l > 0 { mask } else { 0 };
+	    let threshold = if lay.binarization.coverage {
+		lay.binarization.threshold
+	    } else {
+		127
+	    };
+	    ndarray::Zip::from(&mut layers).and(&copper).for_each(|m,&c| {
+		if c > threshold {
+		    *m |= mask;
 		}
-	    }
-	    layers_opt = Some(layers);
+	    });
+	    coverage.push(if lay.binarization.coverage { Some(copper) } else { None });
 	}
-	let num_layers = lay_fns.len();
-	let layers = layers_opt.ok_or_else(|| error("No layers"))?;
-	Ok(Self { num_layers,layers })
+	Ok(Self { num_layers,layers,coverage })
     }
 
-    pub fn connected_components(&self)->Vec<ConnectedComponents> {
-	let mut components = Vec::new();
-	for ilay in 0..self.num_layers {
-	    let cc = ConnectedComponents::from_array(&self.layers,1 << ilay);
-	    components.push(cc);
-	}
-	components
-    }
-}
-
-#[derive(Copy,Clone,Debug,PartialEq,PartialOrd,Ord,Eq)]
-struct CellId {
-    iy:i16,
-    ix:i16
-}
-
-impl CellId {
-    pub fn neighbours(&self)->[Self;4] {
-	let &Self { iy,ix } = self;
-	[
-	    Self { iy:iy - 1, ix },
-	    Self { iy:iy + 1, ix },
-	    Self { iy:iy, ix:ix - 1 },
-	    Self { iy:iy, ix:ix + 1 }
-	]
-    }
-}
-
-impl From<(usize,usize)> for CellId {
-    fn from((iy,ix):(usize,usize))->Self {
-	Self { iy:iy as i16,ix:ix as i16 }
-    }
-}
-
-impl Into<(usize,usize)> for CellId {
-    fn into(self)->(usize,usize) {
-	(self.iy as usize,
-	 self.ix as usize)
-    }
-}
-
-struct ConnectedComponents {
-    components:Vec<BTreeSet<CellId>>
-}
-
-impl ConnectedComponents {
-    pub fn from_array(a:&Array2<u16>,mask:u16)->Self {
-	let (ny,nx) = a.dim();
-	let mut components = Vec::new();
-	let mut remaining : BTreeSet<CellId> = BTreeSet::new();
-	let p = (ny*nx + 63) & !63;
-	let mut visited : Array1<u64> = Array1::zeros(p);
-
-	for (idx,x) in a.indexed_iter() {
-	    if x & mask != 0 {
-		remaining.insert(idx.into());
+    /// Applies the etch offsets of the layers, for pixels of the
+    /// given size in millimeters
+    pub fn etch(&mut self,lays:&[config::Layer],delta:Real) {
+	for (ilay,lay) in lays.iter().enumerate() {
+	    if lay.etch_offset == 0.0 {
+		continue;
 	    }
-	}
-
-	let mut component = Vec::new();
-	let mut active = Vec::new();
-
-	loop {
-	    if let Some(k) = remaining.pop_first() {
-		component.clear();
-		active.clear();
-		active.push(k);
-		loop {
-		    if let Some(k) = active.pop() {
-			let q = (k.iy as usize * nx) + k.ix as usize;
-			visited[q >> 6] |= 1 << (q & 63);
-			remaining.remove(&k);
-			component.push(k);
-			for c in k.neighbours() {
-			    if c.iy >= 0 && c.ix >= 0 {
-				let iy = c.iy as usize;
-				let ix = c.ix as usize;
-				let r = iy * nx + ix;
-				let m = 1 << (r & 63);
-				if visited[r >> 6] & m == 0 &&
-				    a[[iy,ix]] & mask != 0 {
-				    visited[r >> 6] |= m;
-				    active.push(c);
-				}
-			    }
-			}
-		    } else {
-			break;
-		    }
-		}
-		let c : BTreeSet<CellId> = component.iter().cloned().collect();
-		components.push(c);
+	    let mut r = lay.etch_offset * 1e-3 / delta;
+	    if self.coverage[ilay].is_some() {
+		info!("Offsetting layer {} ({}) by {} um, {:.2} pixels",
+		      ilay,lay.name,lay.etch_offset,r);
 	    } else {
-		break;
+		let rounded = r.round();
+		if r.abs() < 1.0 {
+		    warn!("Layer {} ({}): etch offset of {} um is {:.2} pixels, \
+			   rounded to {}; use coverage for offsets below a pixel",
+			  ilay,lay.name,lay.etch_offset,r,rounded as i64);
+		} else {
+		    info!("Offsetting layer {} ({}) by {} um, {:.2} pixels, \
+			   rounded to {}",
+			  ilay,lay.name,lay.etch_offset,r,rounded as i64);
+		}
+		r = rounded;
 	    }
+	    morphology::offset(&mut self.layers,1 << ilay,r,self.coverage[ilay].as_mut());
 	}
-	
-	Self {
-	    components
+    }
+
+    pub fn crop(&mut self,w:&Window) {
+	self.layers = self.layers
+	    .slice(s![w.iy0..w.iy1,w.ix0..w.ix1])
+	    .to_owned();
+	for c in self.coverage.iter_mut().flatten() {
+	    *c = c.slice(s![w.iy0..w.iy1,w.ix0..w.ix1]).to_owned();
 	}
     }
 
-    pub fn dump(&self) {
-	for (icom,com) in self.components.iter().enumerate() {
-	    println!("  {:05} {:10}",icom,com.len());
+    pub fn connected_components(&self,conns:&[Connectivity])
+				->Res<Vec<ConnectedComponents>> {
+	let mut components = Vec::new();
+	for (ilay,&conn) in conns.iter().enumerate().take(self.num_layers) {
+	    let cc = ConnectedComponents::from_array(&self.layers,1 << ilay,conn)?;
+	    components.push(cc);
 	}
+	Ok(components)
     }
 }
 
@@ -232,19 +245,32 @@ fn main0()->Res<()> {
 
     let config_fn : String = args.value_from_str("--config")?;
     info!("Loading configuration from {}",config_fn);
-    let config = Config::load(&config_fn)?;
-
-    let lay_fns : Vec<String> =
-	config.layers
-	.iter()
-	.map(|l| format!("{}/{}",
-			 config.input,
-			 l.bitmap))
-	.collect();
-    let artwork = Artwork::new(&lay_fns)?;
-    let (ny,nx) = artwork.layers.dim();
-    let nlay = artwork.num_layers;
-    info!("Dimensions: {} x {}, number of layers: {}",ny,nx,nlay);
+    let register = args.contains("--register");
+    let check_alignment = args.contains("--check-alignment");
+    let save_config_fn : Option<String> = args.opt_value_from_str("--save-config")?;
+    let queries : Vec<String> = args.values_from_str("--query")?;
+    let cut : Option<String> = args.opt_value_from_str("--cut")?;
+    let mut config = Config::load(&config_fn)?;
+    if let Some(cut) = cut {
+	let u : Vec<Real> = cut.split(',')
+	    .map(|v| v.trim().parse())
+	    .collect::<Result<_,_>>()
+	    .map_err(|_| error(&format!("Invalid cut line {}",cut)))?;
+	let &[x0,y0,x1,y1] = u.as_slice() else {
+	    return Err(error(&format!("The cut line {} should be x0,y0,x1,y1",cut)));
+	};
+	config.cross_section = Some(config::CrossSection {
+	    from:config::Point { x:x0,y:y0 },
+	    to:config::Point { x:x1,y:y1 },
+	    ..config.cross_section.unwrap_or_default()
+	});
+    }
+    let nlay = config.layers.len();
+    if !config.stackup.is_empty() && config.stackup.len() + 1 != nlay {
+	return Err(error(&format!(
+	    "The stackup has {} dielectrics but {} layers need {}",
+	    config.stackup.len(),nlay,nlay.saturating_sub(1))));
+    }
 
     info!("Creating output directory {}",config.output);
     std::fs::create_dir_all(&config.output)?;
@@ -279,7 +305,153 @@ fn main0()->Res<()> {
 	}
     }
 
-    let delta = 25.4 / config.dpi;
+    let etched = config.layers.iter().any(|l| l.etch_offset != 0.0);
+    let (ex,nominal) = match &config.bands {
+	Some(bands) => {
+	    if register || check_alignment {
+		warn!("Registration and alignment checks are not available \
+		       with bands");
+	    }
+	    (bands::extract(&config,bands,&net_infos)?,None)
+	},
+	None if etched => {
+	    // The per-layer outputs of the nominal pass go to their own
+	    // directory so that the etched pass does not overwrite them
+	    let output = config.output.clone();
+	    config.output = format!("{}/nominal",output);
+	    std::fs::create_dir_all(&config.output)?;
+	    info!("Extracting nominal artwork to {}",config.output);
+	    let nominal = extract(&mut config,&net_infos,register,
+				  check_alignment,false)?;
+	    config.output = output;
+	    info!("Extracting etched artwork");
+	    (extract(&mut config,&net_infos,false,false,true)?,Some(nominal))
+	},
+	None => (extract(&mut config,&net_infos,register,check_alignment,false)?,
+		 None)
+    };
+
+    if let Some(path) = save_config_fn {
+	info!("Saving configuration to {}",path);
+	let u = ron::ser::to_string_pretty(&config,
+					   ron::ser::PrettyConfig::default())?;
+	std::fs::write(path,u)?;
+    }
+
+    report(&config,&ex,nominal.as_ref(),&queries)
+}
+
+/// Net names of the components of each layer and their overlaps, from
+/// which capacitances are estimated
+pub struct Extraction {
+    pub grid:Grid,
+    pub window:Option<Window>,
+    /// Net name of each component of each layer
+    pub names:Vec<Vec<Option<String>>>,
+    /// Regions shared by components of layers ilay and jlay and not
+    /// shielded by layers between them, indexed by (ilay,jlay)
+    pub overlaps:BTreeMap<(usize,usize),BTreeMap<(usize,usize),Overlap>>,
+    /// Facing edges of components of each layer
+    pub coplanar:Vec<BTreeMap<(usize,usize),coplanar::Coupling>>,
+    /// Component labels of each layer, kept for the field solvers
+    /// and cross-sections
+    pub labels:Vec<Array2<u32>>,
+    /// Nets having copper or flash points outside of the region of
+    /// interest
+    pub partial_nets:BTreeSet<String>
+}
+
+/// Loads the layer bitmaps in memory, labels their components and
+/// matches them to nets.  Etch offsets are applied if etch is set.
+fn extract(config:&mut Config,net_infos:&[NetInfos],
+	   register:bool,check_alignment:bool,etch:bool)->Res<Extraction> {
+    let mut artwork = Artwork::new(&config.input,&config.layers,
+				   &config.origin,config.dpi)?;
+    let (ny,nx) = artwork.layers.dim();
+    let nlay = artwork.num_layers;
+
+    let mut grid = Grid::new(ny,nx,&config.origin,config.dpi);
+    if register {
+	info!("Registering bitmaps to Gerber flash points");
+	let reg = registration::register(&artwork.layers,&grid,net_infos,
+					 &config.registration)?;
+	info!("Fitted origin ({:.4},{:.4}), dpi {:.3}, residual RMS {:.4} mm, \
+	       {} of {} flash points on copper",
+	      reg.grid.x0,reg.grid.y0,reg.dpi(),reg.rms,
+	      reg.n_hits,reg.n_points);
+	let reg_path = format!("{}/registration.txt",config.output);
+	info!("Writing registration report to {}",reg_path);
+	let fd = File::create(reg_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Origin {} {}",reg.grid.x0,reg.grid.y0)?;
+	writeln!(fd,"# DPI {}",reg.dpi())?;
+	writeln!(fd,"# RMS residual {} mm",reg.rms)?;
+	writeln!(fd,"# Flash points on copper {} of {}",reg.n_hits,reg.n_points)?;
+	for r in reg.residuals.iter() {
+	    writeln!(fd,"{} {} {:+.4} {:+.4}{}",
+		     r.x,r.y,r.dx,r.dy,
+		     if r.inlier { "" } else { " outlier" })?;
+	}
+	config.origin = config::Point { x:reg.grid.x0,y:reg.grid.y0 };
+	config.dpi = reg.dpi();
+	grid = reg.grid;
+	if config.layers.iter().any(|l| l.has_own_geometry()) {
+	    info!("Resampling layers onto the registered grid");
+	    artwork = Artwork::new(&config.input,&config.layers,
+				   &config.origin,config.dpi)?;
+	}
+    }
+
+    if etch {
+	artwork.etch(&config.layers,grid.delta);
+    }
+
+    let window = match &config.roi {
+	Some(roi) => {
+	    let w = grid.window(roi)?;
+	    info!("Cropping to region of interest: rows {}..{}, columns {}..{}",
+		  w.iy0,w.iy1,w.ix0,w.ix1);
+	    artwork.crop(&w);
+	    grid = grid.sub(&w);
+	    Some(w)
+	},
+	None => None
+    };
+    let (ny,nx) = artwork.layers.dim();
+    info!("Dimensions: {} x {}, number of layers: {}",ny,nx,nlay);
+
+    if check_alignment {
+	info!("Checking inter-layer alignment");
+	let als = alignment::check(&artwork.layers,nlay,&grid,net_infos,
+				   &config.alignment)?;
+	let align_path = format!("{}/alignment.txt",config.output);
+	info!("Writing alignment report to {}",align_path);
+	let fd = File::create(align_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Layer, offset relative to first layer in mm (x,y), \
+		      peak correlation, mean pad offset from Gerber in mm (x,y)")?;
+	for (ilay,al) in als.iter().enumerate() {
+	    let (dy,dx) = al.offset;
+	    write!(fd,"{} {} {:+.4} {:+.4} {:.3}",
+		   ilay,config.layers[ilay].name,
+		   dx * grid.delta,-dy * grid.delta,
+		   al.correlation)?;
+	    if let Some((dy,dx)) = al.gerber_offset {
+		write!(fd," {:+.4} {:+.4}",dx * grid.delta,-dy * grid.delta)?;
+	    }
+	    writeln!(fd)?;
+	}
+	let anchors = alignment::common_points(net_infos);
+	for ilay in 1..nlay {
+	    let img = alignment::overlay(&artwork.layers,ilay,&grid,&anchors);
+	    ndarray_image::save_image(format!("{}/align-{}-{}.png",
+					       config.output,
+					       ilay,
+					       config.layers[ilay].name),
+				      img.view(),
+				      ndarray_image::Colors::Rgb)?;
+	}
+    }
 
     // Origin at bottom-left corner
     // Thus
@@ -290,17 +462,37 @@ fn main0()->Res<()> {
     // ix = (X - X0)/delta - 0.5
     // iy = ny - (Y - Y0)/delta - 0.5
 
+    // Nets having copper or flash points outside of the region of
+    // interest
+    let mut partial_nets : BTreeSet<String> = BTreeSet::new();
+
     info!("Computing connected components");
-    let cc = artwork.connected_components();
-    let mut component_ids_per_layer = Array3::zeros((nlay,ny,nx));
+    let conns : Vec<Connectivity> = config.layers.iter()
+	.map(|l| l.connectivity.unwrap_or(config.connectivity))
+	.collect();
+    let mut cc = artwork.connected_components(&conns)?;
+    for ilay in 0..nlay {
+	if conns[ilay] == Connectivity::Four {
+	    let merged = cc[ilay].diagonal_merges();
+	    if merged > 0 {
+		warn!("Layer {} ({}): {} components would merge under \
+		       8-connectivity; resolution may be too low",
+		      ilay,config.layers[ilay].name,merged);
+	    }
+	}
+    }
     let mut component_names_per_layer : Vec<Vec<Option<String>>> = Vec::new();
     let mut xw = Xorwow::new(1);
+    let holes = drill::load_all(config)?;
+    let mut n_shorts = 0;
+    let mut hits_per_layer = Vec::new();
+    let mut couplings = Vec::new();
 
     info!("Marking components");
     for ilay in 0..nlay {
 	let lname = &config.layers[ilay].name;
-	let ccs = &cc[ilay];
-	let m = ccs.components.len();
+	let ccs = &mut cc[ilay];
+	let m = ccs.len();
 	info!("Layer {} ({}), number of components: {}",ilay,lname,m);
 
 	let mut palette = Array2::zeros((m,3));
@@ -310,90 +502,65 @@ fn main0()->Res<()> {
 	    palette[[i,1]] = ((x >> 8) & 255) as u8;
 	    palette[[i,2]] = (x & 255) as u8;
 	}
-	let mut img : Array3<u8> = Array3::zeros((ny,nx,3));
-
-	for (icom,com) in ccs.components.iter().enumerate() {
-	    let r = palette[[icom,0]];
-	    let g = palette[[icom,1]];
-	    let b = palette[[icom,2]];
-	    for &cid in com.iter() {
-		let (iy,ix) : (usize,usize) = cid.into();
-		img[[iy,ix,0]] = r;
-		img[[iy,ix,1]] = g;
-		img[[iy,ix,2]] = b;
-		component_ids_per_layer[[ilay,iy,ix]] = icom + 1;
-	    }
-	}
+	let mut matches = matching::points(&grid,&net_infos[ilay]);
+	matching::locate(config,&grid,&ccs.labels,&mut matches);
+	let (mut component_names,n,hits) = matching::name_components(
+	    config,ilay,&grid,m,&matches,|l| l as usize,
+	    window.as_ref().map(|_| &mut partial_nets))?;
+	n_shorts += n;
+	hits_per_layer.push(hits);
 
-	let mut component_names : Vec<Option<String>> = vec![None;m];
+	let extents : Vec<islands::Extent> = ccs.stats.iter().map(|st| st.into()).collect();
+	islands::unnamed(config,ilay,&mut component_names,&extents,&grid,
+			 Some(&ccs.labels))?;
 
-	let x0 = config.origin.x;
-	let y0 = config.origin.y;
-
-	// Try to match components
-	info!("Matching components to nets");
-	let match_path = format!("{}/net-match-{}-{}.txt",
-				  config.output,
-				  ilay,lname);
-	let fd = File::create(match_path)?;
-	let mut fd = BufWriter::new(fd);
-	let mut n_out_of_bounds = 0;
-	for (name,points) in net_infos[ilay].index.iter() {
-	    write!(fd,"{} -> ",name)?;
-	    for &gerber::Point { x, y } in points.iter() {
-		let ixf = ((x - x0)/delta - 0.5).floor();
-		let iyf = (ny as f64 - (y - y0)/delta - 0.5).floor();
-		write!(fd,"  {},{} ({},{})",x,y,ixf,iyf)?;
-		if 0.0 <= ixf && 0.0 <= iyf {
-		    let ix = ixf as usize;
-		    let iy = iyf as usize;
-		    if ix < nx && iy < ny {
-			let icom = component_ids_per_layer[[ilay,iy,ix]];
-			if icom > 0 {
-			    component_names[icom - 1] = Some(name.clone());
-			}
-			write!(fd,":{}",icom)?;
-		    } else {
-			n_out_of_bounds += 1;
-			write!(fd,"? (out of bounds, positive)")?;
+	if let Some(w) = &window {
+	    for ((iy,ix),&l) in ccs.labels.indexed_iter() {
+		if l > 0 && w.on_cut_edge(iy,ix) {
+		    if let Some(name) = &component_names[l as usize - 1] {
+			partial_nets.insert(name.clone());
 		    }
-		} else {
-		    n_out_of_bounds += 1;
-		    write!(fd,"? (out of bounds, negative)")?;
 		}
 	    }
-	    writeln!(fd)?;
-	}
-
-	if n_out_of_bounds > 0 {
-	    error!("Number of components that could not be matched: {}; \
-		    check origin and dpi",
-		   n_out_of_bounds);
 	}
 
 	component_names_per_layer.push(component_names);
 
+	// Holes are drilled after matching, as flash points lie in them;
+	// the component statistics are left undrilled
+	drill::clear(&mut ccs.labels,&grid,&holes,0);
+
+	couplings.push(match &config.coplanar {
+	    Some(cp) => coplanar::couplings(&ccs.labels,&component_names_per_layer[ilay],
+					    (cp.distance / grid.delta).round() as usize,
+					    (cp.width_max / grid.delta).round() as usize),
+	    None => BTreeMap::new()
+	});
+
+	let mut img : Array3<u8> = Array3::zeros((ny,nx,3));
+
+	for ((iy,ix),&l) in ccs.labels.indexed_iter() {
+	    if l > 0 {
+		let icom = l as usize - 1;
+		img[[iy,ix,0]] = palette[[icom,0]];
+		img[[iy,ix,1]] = palette[[icom,1]];
+		img[[iy,ix,2]] = palette[[icom,2]];
+	    }
+	}
+
 	// Add marker
 	match config.mark {
 	    Some(config::Point{x,y}) => {
-		// X = 
-		let ixf = ((x - x0)/delta - 0.5).floor();
-		let iyf = (ny as f64 - (y - y0)/delta - 0.5).floor();
-		if 0.0 <= ixf && 0.0 <= iyf {
-		    let ix = ixf as usize;
-		    let iy = iyf as usize;
-		    if ix < nx && iy < ny {
-			info!("Marking ix = {}, iy = {}",ix,iy);
-			for ix2 in 0..nx {
-			    img[[iy,ix2,0]] ^= 255;
-			}
-			for iy2 in 0..ny {
-			    img[[iy2,ix,0]] ^= 255;
-			}
-		    } else {
-			info!("Not marking, ix = {}, iy = {}",ix,iy);
+		if let Some((iy,ix)) = grid.pixel(x,y) {
+		    info!("Marking ix = {}, iy = {}",ix,iy);
+		    for ix2 in 0..nx {
+			img[[iy,ix2,0]] ^= 255;
+		    }
+		    for iy2 in 0..ny {
+			img[[iy2,ix,0]] ^= 255;
 		    }
 		} else {
+		    let (iyf,ixf) = grid.index(x,y);
 		    info!("Not marking, ixf = {}, iyf = {}",ixf,iyf);
 		}
 	    },
@@ -407,13 +574,167 @@ fn main0()->Res<()> {
 				  ndarray_image::Colors::Rgb)?;
     }
 
+
+    matching::check(config,&hits_per_layer,&holes,n_shorts)?;
+
+    let overlaps = config.layer_pairs().into_iter()
+	.map(|(ilay,jlay)| {
+	    let shield = components::shield(&cc,ilay,jlay);
+	    ((ilay,jlay),
+	     components::overlaps(&cc[ilay],&cc[jlay],
+				  artwork.coverage[ilay].as_ref(),
+				  artwork.coverage[jlay].as_ref(),
+				  shield.as_ref()))
+	})
+	.collect();
+
+    let labels = if config.field.is_some() || config.random_walk.is_some()
+	|| config.cross_section.is_some() {
+	cc.into_iter().map(|c| c.labels).collect()
+    } else {
+	Vec::new()
+    };
+
+    Ok(Extraction {
+	grid,
+	window,
+	names:component_names_per_layer,
+	overlaps,
+	coplanar:couplings,
+	labels,
+	partial_nets
+    })
+}
+
+/// Capacitances between pairs of registered nets, indexed by their
+/// ordered identifiers.  With fringing, the edge capacitance of the
+/// boundary of the overlaps is added, using the edge term of the
+/// Sakurai-Tamaru formula for a line above a plane.
+fn capacitances(config:&Config,ex:&Extraction,net_names:&Registry,
+		classes:&NetClasses,fringing:Option<&config::Fringing>)
+		->BTreeMap<(usize,usize),Real> {
+    let inc = net_names.find_id("N/C").unwrap();
+    let mut caps : BTreeMap<(usize,usize),f64> = BTreeMap::new();
+    
+    for (&(ilay,jlay),overlaps) in ex.overlaps.iter() {
+	let d = config.dielectric_between(ilay,jlay);
+	// Capacitance per meter of edge
+	let edge = fringing
+	    .map(|f| 8.854e-12 * d.eps_rel * 1.40
+		 * (f.copper * 1e-3 / d.thickness).powf(0.222))
+	    .unwrap_or(0.0);
+	for (&(icomi,icomj),ov) in overlaps.iter() {
+	    let namei = &ex.names[ilay][icomi];
+	    let namej = &ex.names[jlay][icomj];
+	    let namei = namei.as_deref().and_then(|u| classes.conductor(u));
+	    let namej = namej.as_deref().and_then(|u| classes.conductor(u));
+	    if let (Some(namei),Some(namej)) = (namei,namej) {
+		let inet = net_names.find_id(&namei).unwrap();
+		let jnet = net_names.find_id(&namej).unwrap();
+		if inet == inc || jnet == inc || inet == jnet {
+		    continue;
+		}
+		let area = ov.area * ex.grid.pixel_area();
+		let cap = 8.854e-12 * d.eps_rel * area
+		    / (d.thickness * 1e-3)
+		    + edge * ov.perimeter as Real * ex.grid.delta * 1e-3;
+
+		let a = inet.min(jnet);
+		let b = inet.max(jnet);
+		let c = caps.entry((a,b)).or_insert(0.0);
+		*c += cap;
+	    }
+	}
+    }
+    caps
+}
+
+/// Facing edges between pairs of registered nets, indexed by layer
+/// and ordered net identifiers
+fn coplanar_couplings(ex:&Extraction,net_names:&Registry,classes:&NetClasses)
+		      ->BTreeMap<(usize,usize,usize),coplanar::Coupling> {
+    let inc = net_names.find_id("N/C").unwrap();
+    let mut res : BTreeMap<(usize,usize,usize),coplanar::Coupling> = BTreeMap::new();
+    for (ilay,couplings) in ex.coplanar.iter().enumerate() {
+	for (&(icoma,icomb),c) in couplings.iter() {
+	    let namea = ex.names[ilay][icoma].as_deref().and_then(|u| classes.conductor(u));
+	    let nameb = ex.names[ilay][icomb].as_deref().and_then(|u| classes.conductor(u));
+	    if let (Some(namea),Some(nameb)) = (namea,nameb) {
+		let inet = net_names.find_id(&namea).unwrap();
+		let jnet = net_names.find_id(&nameb).unwrap();
+		if inet == inc || jnet == inc || inet == jnet {
+		    continue;
+		}
+		let e = res.entry((ilay,inet.min(jnet),inet.max(jnet))).or_default();
+		e.run += c.run;
+		e.gap += c.gap;
+		e.factor += c.factor;
+	    }
+	}
+    }
+    res
+}
+
+/// Capacitance of facing edges on layer ilay
+fn coplanar_cap(config:&Config,ex:&Extraction,ilay:usize,c:&coplanar::Coupling)->Real {
+    8.854e-12 * coplanar::eps_eff(config,ilay) * c.factor * ex.grid.delta * 1e-3
+}
+
+/// Coplanar capacitances between pairs of registered nets, summed over
+/// the layers
+fn coplanar_capacitances(config:&Config,ex:&Extraction,
+			 couplings:&BTreeMap<(usize,usize,usize),coplanar::Coupling>)
+			 ->BTreeMap<(usize,usize),Real> {
+    let mut caps : BTreeMap<(usize,usize),Real> = BTreeMap::new();
+    for (&(ilay,inet,jnet),c) in couplings.iter() {
+	*caps.entry((inet,jnet)).or_insert(0.0) += coplanar_cap(config,ex,ilay,c);
+    }
+    caps
+}
+
+/// Sum of capacitances between pairs of nets
+fn sum_caps(cs:&[&BTreeMap<(usize,usize),Real>])->BTreeMap<(usize,usize),Real> {
+    let mut res : BTreeMap<(usize,usize),Real> = BTreeMap::new();
+    for (&k,&cap) in cs.iter().flat_map(|c| c.iter()) {
+	*res.entry(k).or_insert(0.0) += cap;
+    }
+    res
+}
+
+/// Conductor of each pixel of each layer: the position of its net or
+/// aggregated class among the selected ones, or their number for
+/// other copper
+fn selected_conductors(ex:&Extraction,classes:&NetClasses,nets:&[String])
+		       ->Vec<Array2<Option<usize>>> {
+    let n = nets.len();
+    ex.labels.iter().zip(ex.names.iter())
+	.map(|(labels,names)| {
+	    let of_component : Vec<usize> = names.iter()
+		.map(|u| u.as_deref()
+		     .and_then(|u| classes.conductor(u))
+		     .and_then(|u| nets.iter().position(|v| *v == u))
+		     .unwrap_or(n))
+		.collect();
+	    labels.mapv(|l| if l > 0 { Some(of_component[l as usize - 1]) } else { None })
+	})
+	.collect()
+}
+
+/// Estimates capacitances from the extraction and writes the net and
+/// capacitance reports, comparing with the nominal extraction if
+/// given, and prints the capacitances of the queried nets
+fn report(config:&Config,ex:&Extraction,nominal:Option<&Extraction>,
+	  queries:&[String])->Res<()> {
     info!("Computing net registry");
     let mut net_names = Registry::new();
-    let inc = net_names.register("N/C");
-    for ilay in 0..nlay {
-	for icomi in 0..cc[ilay].components.len() {
-	    if let Some(name) = &component_names_per_layer[ilay][icomi] {
-		net_names.register(name);
+    let classes = NetClasses::new(&config.net_classes)?;
+    net_names.register("N/C");
+    for e in std::iter::once(ex).chain(nominal) {
+	for names in e.names.iter() {
+	    for name in names.iter().flatten() {
+		if let Some(u) = classes.conductor(name) {
+		    net_names.register(&u);
+		}
 	    }
 	}
     }
@@ -431,62 +752,39 @@ fn main0()->Res<()> {
     }
 
     // Capacitances
-    info!("Estimating mutual capacitances for adjacent layers");
-    let mut caps : BTreeMap<(usize,usize),f64> = BTreeMap::new();
-    
-    for ilay in 0..nlay {
-	let mut jlays = Vec::new();
-	if ilay > 1 {
-	    jlays.push(ilay - 1);
-	}
-	if ilay + 1 < nlay {
-	    jlays.push(ilay + 1);
-	}
-	let cci = &cc[ilay];
-
-	for jlay in jlays {
-	    let ccj = &cc[jlay];
-	    for (icomi,comi) in cci.components.iter().enumerate() {
-		if let Some(namei) = &component_names_per_layer[ilay][icomi] {
-		    let inet = net_names.find_id(namei).unwrap();
-		    if inet == inc {
-			continue;
-		    }
-		    for (icomj,comj) in ccj.components.iter().enumerate() {
-			if let Some(namej) = &component_names_per_layer[jlay][icomj] {
-			    let jnet = net_names.find_id(namej).unwrap();
-			    if jnet == inc {
-				continue;
-			    }
-			    if inet != jnet {
-				let n = comi.intersection(comj).count();
-				if n > 0 {
-				    let area = n as f64 * delta * delta * 1e-6;
-				    let cap = 8.854e-12 * config.eps_rel * area
-					/ (config.thickness * 1e-3);
-
-				    let a = inet.min(jnet);
-				    let b = inet.max(jnet);
-				    let c = caps.entry((a,b)).or_insert(0.0);
-				    *c += cap;
-
-				}
-			    }
-			}
-		    }
-		}
-	    }
-	}
+    info!("Estimating mutual capacitances for {} layer pairs",ex.overlaps.len());
+    for ilay in 0..config.layers.len().saturating_sub(1) {
+	let d = config.dielectric(ilay);
+	info!("Between {} and {}: {} mm, eps_rel {}{}",
+	      config.layers[ilay].name,config.layers[ilay + 1].name,
+	      d.thickness,d.eps_rel,
+	      d.material.map(|m| format!(" ({})",m)).unwrap_or_default());
     }
+    let caps = capacitances(config,ex,&net_names,&classes,config.fringing.as_ref());
+
+    // Coplanar capacitances, per layer and in total
+    let couplings = coplanar_couplings(ex,&net_names,&classes);
+    let coplanar_caps = coplanar_capacitances(config,ex,&couplings);
+    let total_caps = sum_caps(&[&caps,&coplanar_caps]);
 
-    let mut sig_caps : BTreeMap<i64,(usize,usize)> = BTreeMap::new();
+    // Pairs to report, with a flag for coplanar coupling
+    let mut sig_caps : BTreeSet<(i64,usize,usize,bool)> = BTreeSet::new();
     let scale = 1e-18;
-    for (&(inet,jnet),&cap) in caps.iter() {
-	if cap >= config.cap_min {
-	    let cap_i = (cap/scale).round() as i64;
-	    sig_caps.insert(cap_i,(inet,jnet));
+    for (coplanar,cs) in [(false,&caps),(true,&coplanar_caps)] {
+	for (&(inet,jnet),&cap) in cs.iter() {
+	    if classes.is_reference(net_names.find_name(inet).unwrap()) &&
+		classes.is_reference(net_names.find_name(jnet).unwrap()) {
+		continue;
+	    }
+	    if cap >= config.cap_min {
+		let cap_i = (cap/scale).round() as i64;
+		sig_caps.insert((cap_i,inet,jnet,coplanar));
+	    }
 	}
     }
+    let partial_nets : BTreeSet<String> = ex.partial_nets.iter()
+	.filter_map(|u| classes.conductor(u))
+	.collect();
 
     {
 	let mutcaps_path = format!("{}/mutcaps.txt",config.output);
@@ -495,15 +793,279 @@ fn main0()->Res<()> {
 	      mutcaps_path);
 	let fd = File::create(mutcaps_path)?;
 	let mut fd = BufWriter::new(fd);
-	for (&cap_i,&(inet,jnet)) in sig_caps.iter() {
+	for &(cap_i,inet,jnet,coplanar) in sig_caps.iter() {
 	    let cap = cap_i as f64 * (scale/1e-12);
-	    writeln!(fd,
-		     "{:7.3} pF\t{}\t{}",
-		     cap,
+	    let namei = net_names.find_name(inet).unwrap();
+	    let namej = net_names.find_name(jnet).unwrap();
+	    write!(fd,"{:7.3} pF\t{}\t{}",cap,namei,namej)?;
+	    if coplanar {
+		write!(fd,"\tcoplanar")?;
+	    }
+	    if partial_nets.contains(namei) || partial_nets.contains(namej) {
+		write!(fd,"\tpartial")?;
+	    }
+	    writeln!(fd)?;
+	}
+    }
+
+    if config.coplanar.is_some() {
+	let coplanar_path = format!("{}/coplanar.txt",config.output);
+	info!("Writing coplanar couplings to {}",coplanar_path);
+	let fd = File::create(coplanar_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Capacitance, parallel run length, mean gap, layer, nets")?;
+	for (&(ilay,inet,jnet),c) in couplings.iter() {
+	    let cap = coplanar_cap(config,ex,ilay,c);
+	    if cap < config.cap_min {
+		continue;
+	    }
+	    writeln!(fd,"{:7.3} pF\t{:8.3} mm\t{:6.3} mm\t{}\t{}\t{}",
+		     cap/1e-12,
+		     c.run as Real * ex.grid.delta,
+		     c.gap / c.run as Real * ex.grid.delta,
+		     config.layers[ilay].name,
+		     net_names.find_name(inet).unwrap(),
+		     net_names.find_name(jnet).unwrap())?;
+	}
+    }
+
+    if let Some(f) = &config.fringing {
+	let raw_caps = capacitances(config,ex,&net_names,&classes,None);
+	let fringing_path = format!("{}/fringing.txt",config.output);
+	info!("Writing capacitances with and without fringing to {}",fringing_path);
+	let fd = File::create(fringing_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Copper thickness {} um",f.copper)?;
+	writeln!(fd,"# Parallel plate, with fringing, relative change, nets")?;
+	for &(_,inet,jnet,_) in sig_caps.iter().filter(|p| !p.3) {
+	    let c0 = raw_caps.get(&(inet,jnet)).copied().unwrap_or(0.0);
+	    let c1 = caps[&(inet,jnet)];
+	    writeln!(fd,"{:7.3} pF\t{:7.3} pF\t{:+7.2}%\t{}\t{}",
+		     c0/1e-12,c1/1e-12,100.0*(c1 - c0)/c0,
+		     net_names.find_name(inet).unwrap(),
+		     net_names.find_name(jnet).unwrap())?;
+	}
+    }
+
+    // Maxwell matrix of all nets but N/C, in registry order
+    let mut mx = Maxwell::new(net_names.id_to_name[1..].to_vec());
+    for (&(inet,jnet),&cap) in total_caps.iter() {
+	mx.add_mutual(inet - 1,jnet - 1,cap);
+    }
+    let maxwell_path = format!("{}/maxwell.csv",config.output);
+    info!("Writing the Maxwell capacitance matrix to {}",maxwell_path);
+    mx.save_csv(&maxwell_path)?;
+    if config.maxwell_dense {
+	let dense_path = format!("{}/maxwell-dense.csv",config.output);
+	info!("Writing the dense Maxwell capacitance matrix to {}",dense_path);
+	mx.save_dense_csv(&dense_path)?;
+    }
+
+    {
+	let totals_path = format!("{}/totals.txt",config.output);
+	info!("Writing total capacitances to {}",totals_path);
+	let fd = File::create(totals_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Total, to reference nets, net")?;
+	for i in 0..mx.len() {
+	    writeln!(fd,"{:7.3} pF\t{:7.3} pF\t{}",
+		     mx.total(i)/1e-12,
+		     mx.to(i,|u| classes.is_reference(u))/1e-12,
+		     mx.names[i])?;
+	}
+    }
+
+    for q in queries.iter() {
+	let i = mx.find(q).ok_or_else(|| error(&format!("Unknown net {}",q)))?;
+	println!("{}: total {:.3} pF, to reference nets {:.3} pF",
+		 q,mx.total(i)/1e-12,mx.to(i,|u| classes.is_reference(u))/1e-12);
+	let mut row : Vec<(Real,usize)> = mx.mutuals(i)
+	    .map(|(j,c)| (c,j))
+	    .filter(|&(c,_)| c > 0.0)
+	    .collect();
+	row.sort_by(|a,b| b.partial_cmp(a).unwrap());
+	for (c,j) in row {
+	    println!("  {:7.3} pF\t{}",c/1e-12,mx.names[j]);
+	}
+    }
+
+    if let Some(fs) = &config.field {
+	let mut selected = Vec::new();
+	for u in fs.nets.iter() {
+	    selected.push(mx.find(u).ok_or_else(|| error(&format!(
+		"Unknown net {} for the field solver",u)))?);
+	}
+	let n = selected.len();
+	let conductors = field::crop(&selected_conductors(ex,&classes,&fs.nets),n,
+				     (fs.lateral_margin / ex.grid.delta).ceil() as usize)?;
+	info!("Solving the field for {} nets",n);
+	let c = field::solve(config,fs,ex.grid.delta,&conductors,n)?;
+
+	let field_path = format!("{}/field.txt",config.output);
+	info!("Writing field-solved capacitances to {}",field_path);
+	let fd = File::create(field_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Maxwell matrix in pF")?;
+	for i in 0..n {
+	    for j in 0..n {
+		write!(fd,"{:9.3} ",c[[i,j]]/1e-12)?;
+	    }
+	    writeln!(fd,"\t{}",fs.nets[i])?;
+	}
+	writeln!(fd,"# Field solution, estimate, nets")?;
+	for i in 0..n {
+	    writeln!(fd,"{:7.3} pF\t{:7.3} pF\t{}\ttotal",
+		     c[[i,i]]/1e-12,mx.total(selected[i])/1e-12,fs.nets[i])?;
+	    for j in i + 1..n {
+		writeln!(fd,"{:7.3} pF\t{:7.3} pF\t{}\t{}",
+			 -c[[i,j]]/1e-12,mx.mutual(selected[i],selected[j])/1e-12,
+			 fs.nets[i],fs.nets[j])?;
+	    }
+	}
+    }
+
+    if let Some(rw) = &config.random_walk {
+	let mut selected = Vec::new();
+	for u in rw.nets.iter() {
+	    selected.push(mx.find(u).ok_or_else(|| error(&format!(
+		"Unknown net {} for random walk extraction",u)))?);
+	}
+	let n = selected.len();
+	let conductors = selected_conductors(ex,&classes,&rw.nets);
+	info!("Random walk extraction for {} nets",n);
+	let (c,hw) = random_walk::solve(config,rw,ex.grid.delta,&conductors,n)?;
+
+	let frw_path = format!("{}/frw.txt",config.output);
+	info!("Writing random walk capacitances to {}",frw_path);
+	let fd = File::create(frw_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Maxwell matrix in pF")?;
+	for i in 0..n {
+	    for j in 0..n {
+		write!(fd,"{:9.3} ",c[[i,j]]/1e-12)?;
+	    }
+	    writeln!(fd,"\t{}",rw.nets[i])?;
+	}
+	writeln!(fd,"# Random walk estimate, {}% confidence interval half-width, \
+		      estimate, nets",100.0*rw.confidence)?;
+	for i in 0..n {
+	    writeln!(fd,"{:7.3} pF\t{:7.3} pF\t{:7.3} pF\t{}\ttotal",
+		     c[[i,i]]/1e-12,hw[[i,i]]/1e-12,mx.total(selected[i])/1e-12,rw.nets[i])?;
+	    for j in i + 1..n {
+		writeln!(fd,"{:7.3} pF\t{:7.3} pF\t{:7.3} pF\t{}\t{}",
+			 -c[[i,j]]/1e-12,hw[[i,j]]/1e-12,
+			 mx.mutual(selected[i],selected[j])/1e-12,
+			 rw.nets[i],rw.nets[j])?;
+	    }
+	}
+    }
+
+    if let Some(cs) = &config.cross_section {
+	let section = Section::new(cs,&ex.grid,&ex.labels,&ex.names,&classes)?;
+	let image_path = format!("{}/section.png",config.output);
+	info!("Writing the cross-section to {}",image_path);
+	ndarray_image::save_image(&image_path,
+				  section.image(config,cs).view(),
+				  ndarray_image::Colors::Rgb)?;
+	let n = section.names.len();
+	if n == 0 {
+	    warn!("The cut line crosses no net");
+	} else {
+	    let lines = section.solve(config,cs)?;
+	    let section_path = format!("{}/section.txt",config.output);
+	    info!("Writing per-unit-length parameters to {}",section_path);
+	    let fd = File::create(section_path)?;
+	    let mut fd = BufWriter::new(fd);
+	    writeln!(fd,"# Cut line from ({},{}) to ({},{}) mm, sampled every {:.4} mm",
+		     cs.from.x,cs.from.y,cs.to.x,cs.to.y,section.step)?;
+	    writeln!(fd,"# Maxwell matrix in pF/m")?;
+	    for i in 0..n {
+		for j in 0..n {
+		    write!(fd,"{:9.3} ",lines.c[[i,j]]/1e-12)?;
+		}
+		writeln!(fd,"\t{}",section.names[i])?;
+	    }
+	    writeln!(fd,"# Impedance with the other nets grounded, effective \
+			  permittivity, delay, net")?;
+	    for i in 0..n {
+		writeln!(fd,"{:8.2} ohm\t{:6.3}\t{:6.3} ps/mm\t{}",
+			 lines.z0(i),lines.eps_eff(i),lines.delay(i),section.names[i])?;
+	    }
+	}
+    }
+
+    if !classes.is_empty() {
+	let mut class_caps : BTreeMap<(&str,&str),Real> = BTreeMap::new();
+	for (&(inet,jnet),&cap) in total_caps.iter() {
+	    let class_name = |inet| classes.conductor_class(net_names.find_name(inet).unwrap())
+		.map(|c| c.name.as_str())
+		.unwrap_or("-");
+	    let ci = class_name(inet);
+	    let cj = class_name(jnet);
+	    *class_caps.entry((ci.min(cj),ci.max(cj))).or_insert(0.0) += cap;
+	}
+	let classes_path = format!("{}/classes.txt",config.output);
+	info!("Writing capacitances between net classes to {}",classes_path);
+	let fd = File::create(classes_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Total capacitance, overlap and coplanar, between nets \
+		      of each pair of classes, - for nets without a class")?;
+	for (&(ci,cj),&cap) in class_caps.iter() {
+	    writeln!(fd,"{:9.3} pF\t{}\t{}",cap/1e-12,ci,cj)?;
+	}
+    }
+
+    if let Some(nominal) = nominal {
+	let nominal_couplings = coplanar_couplings(nominal,&net_names,&classes);
+	let nominal_caps = sum_caps(&[
+	    &capacitances(config,nominal,&net_names,&classes,config.fringing.as_ref()),
+	    &coplanar_capacitances(config,nominal,&nominal_couplings)
+	]);
+	let mut pairs : Vec<(Real,Real,usize,usize)> = nominal_caps.keys()
+	    .chain(total_caps.keys())
+	    .collect::<BTreeSet<_>>()
+	    .into_iter()
+	    .map(|&(inet,jnet)| {
+		let c0 = nominal_caps.get(&(inet,jnet)).copied().unwrap_or(0.0);
+		let c1 = total_caps.get(&(inet,jnet)).copied().unwrap_or(0.0);
+		(c0,c1,inet,jnet)
+	    })
+	    .filter(|&(c0,c1,_,_)| c0.max(c1) >= config.cap_min)
+	    .collect();
+	pairs.sort_by(|a,b| a.partial_cmp(b).unwrap());
+
+	let etch_path = format!("{}/etch.txt",config.output);
+	info!("Writing capacitance changes due to etch offsets to {}",etch_path);
+	let fd = File::create(etch_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Nominal, etched, relative change of the overlap and \
+		      coplanar capacitance, nets")?;
+	for (c0,c1,inet,jnet) in pairs {
+	    write!(fd,"{:7.3} pF\t{:7.3} pF\t",c0/1e-12,c1/1e-12)?;
+	    if c0 > 0.0 {
+		write!(fd,"{:+7.2}%",100.0*(c1 - c0)/c0)?;
+	    } else {
+		write!(fd,"    new")?;
+	    }
+	    writeln!(fd,"\t{}\t{}",
 		     net_names.find_name(inet).unwrap(),
 		     net_names.find_name(jnet).unwrap())?;
 	}
     }
 
+    if let (Some(roi),Some(w)) = (&config.roi,&ex.window) {
+	let roi_path = format!("{}/roi.txt",config.output);
+	info!("Writing nets extending beyond the region of interest to {}",
+	      roi_path);
+	let fd = File::create(roi_path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"# Region of interest ({},{})-({},{}) mm",
+		 roi.p0.x,roi.p0.y,roi.p1.x,roi.p1.y)?;
+	writeln!(fd,"# Rows {}..{}, columns {}..{}",w.iy0,w.iy1,w.ix0,w.ix1)?;
+	for name in ex.partial_nets.iter() {
+	    writeln!(fd,"{}",name)?;
+	}
+    }
+
     Ok(())
 }
@@ -0,0 +1,150 @@
+use log::{info,warn,error};
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{Write,BufWriter}
+};
+
+use crate::{
+    common::*,
+    math::*,
+    config::{Config,ShortPolicy},
+    disk,
+    drill::Hole,
+    gerber::NetInfos,
+    grid::Grid,
+    opens,
+    shorts::{self,Hit}
+};
+
+/// Flash point of a net and the label it landed on
+pub struct PointMatch {
+    pub x:Real,
+    pub y:Real,
+    pub pixel:Option<(usize,usize)>,
+    pub label:u32,
+    /// Rank of the flash point in the Gerber file
+    pub rank:usize,
+    /// Distance to the copper found by the search, in millimeters
+    pub distance:Option<Real>
+}
+
+/// Flash points of each net of a layer, with their pixels and no
+/// label yet
+pub fn points(grid:&Grid,ni:&NetInfos)->Vec<(String,Vec<PointMatch>)> {
+    ni.index.iter()
+	.map(|(name,points)| {
+	    let pms = points.iter().zip(ni.ranks[name].iter())
+		.map(|(p,&rank)| PointMatch {
+		    x:p.x,
+		    y:p.y,
+		    pixel:grid.pixel(p.x,p.y),
+		    label:0,
+		    rank,
+		    distance:None
+		})
+		.collect();
+	    (name.clone(),pms)
+	})
+	.collect()
+}
+
+/// Labels the flash points of a layer with the component they lie
+/// on, or with the nearest one within the matching radius
+pub fn locate(config:&Config,grid:&Grid,labels:&Array2<u32>,
+	      matches:&mut [(String,Vec<PointMatch>)]) {
+    let (ny,nx) = labels.dim();
+    let radius = config.matching.radius / grid.delta;
+    for pm in matches.iter_mut().flat_map(|(_,pms)| pms.iter_mut()) {
+	let Some((iy,ix)) = pm.pixel else { continue };
+	pm.label = labels[[iy,ix]];
+	if pm.label == 0 && radius > 0.0 {
+	    let (iyf,ixf) = grid.index(pm.x,pm.y);
+	    if let Some((jy,jx,d)) = disk::nearest(iyf,ixf,radius,ny,nx,
+						   |jy,jx| labels[[jy,jx]] > 0) {
+		pm.label = labels[[jy,jx]];
+		pm.distance = Some(d * grid.delta);
+	    }
+	}
+    }
+}
+
+/// Writes the flash points of each net of a layer and the components
+/// they matched, given by component(label) counting from 1, to
+/// net-match-<ilay>-<layer>.txt, and names the components after the
+/// nets hitting them.  When cropping to a region of interest, nets
+/// with flash points outside of it are added to partial_nets.
+/// Returns the names, the number of components hit by several nets
+/// and the hits.
+pub fn name_components(config:&Config,ilay:usize,grid:&Grid,
+		       m:usize,matches:&[(String,Vec<PointMatch>)],
+		       component:impl Fn(u32)->usize,
+		       mut partial_nets:Option<&mut BTreeSet<String>>)
+		       ->Res<(Vec<Option<String>>,usize,Vec<Hit>)> {
+    let lname = &config.layers[ilay].name;
+    info!("Matching components to nets");
+    let match_path = format!("{}/net-match-{}-{}.txt",
+			     config.output,
+			     ilay,lname);
+    let fd = File::create(match_path)?;
+    let mut fd = BufWriter::new(fd);
+    let mut n_out_of_bounds = 0;
+    let mut far = Vec::new();
+    let mut hits = Vec::new();
+    for (name,pms) in matches.iter() {
+	write!(fd,"{} -> ",name)?;
+	for pm in pms.iter() {
+	    let (iyf,ixf) = grid.index(pm.x,pm.y);
+	    write!(fd,"  {},{} ({:.1},{:.1})",pm.x,pm.y,ixf,iyf)?;
+	    if let Some(d) = pm.distance {
+		write!(fd,"@{:.3}",d)?;
+		if d > config.matching.warn_distance {
+		    far.push(d);
+		}
+	    }
+	    if pm.pixel.is_some() {
+		let icom = component(pm.label);
+		if icom > 0 {
+		    hits.push(Hit { icom:icom - 1,name:name.clone(),
+				    x:pm.x,y:pm.y,rank:pm.rank });
+		}
+		write!(fd,":{}",icom)?;
+	    } else if let Some(pn) = partial_nets.as_mut() {
+		pn.insert(name.clone());
+		write!(fd,"? (outside region of interest)")?;
+	    } else {
+		n_out_of_bounds += 1;
+		write!(fd,"? (out of bounds)")?;
+	    }
+	}
+	writeln!(fd)?;
+    }
+    if n_out_of_bounds > 0 {
+	error!("Number of components that could not be matched: {}; \
+		check origin and dpi",
+	       n_out_of_bounds);
+    }
+    if !far.is_empty() {
+	warn!("Layer {} ({}): {} flash points matched copper farther than \
+	       {} mm, up to {:.3} mm; check origin and dpi",
+	      ilay,lname,far.len(),config.matching.warn_distance,
+	      far.iter().copied().fold(0.0,Real::max));
+    }
+
+    let shorts_path = format!("{}/shorts-{}-{}.txt",
+			      config.output,
+			      ilay,lname);
+    let (names,n_shorts) = shorts::resolve(m,&hits,config.shorts,&shorts_path)?;
+    Ok((names,n_shorts,hits))
+}
+
+/// Reports nets split into groups not joined through vias, and fails
+/// if components were hit by several nets under the error policy
+pub fn check(config:&Config,hits:&[Vec<Hit>],holes:&[Hole],n_shorts:usize)->Res<()> {
+    opens::check(config,hits,holes)?;
+    if n_shorts > 0 && config.shorts == ShortPolicy::Error {
+	return Err(error(&format!("{} components matched to several nets",
+				  n_shorts)));
+    }
+    Ok(())
+}
@@ -31,4 +31,10 @@ impl Random {
         let dist : Uniform<Real> = Uniform::new(0.0,1.0);
         Random{ rng, dist }
     }
+
+    pub fn from_seed(seed:u64)->Self {
+        let rng = SeedableRng::seed_from_u64(seed);
+        let dist : Uniform<Real> = Uniform::new(0.0,1.0);
+        Random{ rng, dist }
+    }
 }
@@ -0,0 +1,156 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Write,BufWriter}
+};
+
+use crate::{
+    common::*,
+    math::*
+};
+
+/// Maxwell capacitance matrix: the diagonal holds the total
+/// capacitance of each net to all others, and off-diagonal entries
+/// the opposite of the mutual capacitances, in farads.  Only nonzero
+/// mutual capacitances are stored.
+pub struct Maxwell {
+    pub names:Vec<String>,
+    index:BTreeMap<String,usize>,
+    totals:Vec<Real>,
+    /// Mutual capacitances of each net, by other net
+    mutuals:Vec<BTreeMap<usize,Real>>
+}
+
+impl Maxwell {
+    pub fn new(names:Vec<String>)->Self {
+	let n = names.len();
+	let index = names.iter().enumerate().map(|(i,u)| (u.clone(),i)).collect();
+	Self {
+	    names,
+	    index,
+	    totals:vec![0.0;n],
+	    mutuals:vec![BTreeMap::new();n]
+	}
+    }
+
+    pub fn len(&self)->usize {
+	self.names.len()
+    }
+
+    pub fn find(&self,name:&str)->Option<usize> {
+	self.index.get(name).copied()
+    }
+
+    pub fn add_mutual(&mut self,i:usize,j:usize,cap:Real) {
+	*self.mutuals[i].entry(j).or_insert(0.0) += cap;
+	*self.mutuals[j].entry(i).or_insert(0.0) += cap;
+	self.totals[i] += cap;
+	self.totals[j] += cap;
+    }
+
+    pub fn total(&self,i:usize)->Real {
+	self.totals[i]
+    }
+
+    pub fn mutual(&self,i:usize,j:usize)->Real {
+	self.mutuals[i].get(&j).copied().unwrap_or(0.0)
+    }
+
+    /// Nets coupled to net i, with their mutual capacitances
+    pub fn mutuals(&self,i:usize)->impl Iterator<Item=(usize,Real)> + '_ {
+	self.mutuals[i].iter().map(|(&j,&c)| (j,c))
+    }
+
+    /// Capacitance of net i to the nets for which the predicate holds
+    pub fn to<F:Fn(&str)->bool>(&self,i:usize,pred:F)->Real {
+	// Adding 0.0 turns the -0.0 of an empty sum into 0.0
+	self.mutuals(i)
+	    .filter(|&(j,_)| j != i && pred(&self.names[j]))
+	    .map(|(_,c)| c)
+	    .sum::<Real>() + 0.0
+    }
+
+    /// Entry (i,j) of the matrix
+    fn entry(&self,i:usize,j:usize)->Real {
+	if i == j {
+	    self.total(i)
+	} else {
+	    self.mutuals[i].get(&j).map(|&c| -c).unwrap_or(0.0)
+	}
+    }
+
+    /// Writes the nonzero entries of the upper triangle in picofarads
+    /// as CSV, one per row with the names of both nets
+    pub fn save_csv(&self,path:&str)->Res<()> {
+	let fd = File::create(path)?;
+	let mut fd = BufWriter::new(fd);
+	writeln!(fd,"net,net,pF")?;
+	for (i,u) in self.names.iter().enumerate() {
+	    let row = std::iter::once(i)
+		.chain(self.mutuals[i].range(i + 1..).map(|(&j,_)| j));
+	    for j in row {
+		let c = self.entry(i,j);
+		if c != 0.0 {
+		    writeln!(fd,"{},{},{:.6}",csv_field(u),csv_field(&self.names[j]),c/1e-12)?;
+		}
+	    }
+	}
+	Ok(())
+    }
+
+    /// Writes the whole matrix in picofarads as CSV, with the net
+    /// names as first row and column
+    pub fn save_dense_csv(&self,path:&str)->Res<()> {
+	let fd = File::create(path)?;
+	let mut fd = BufWriter::new(fd);
+	write!(fd,"pF")?;
+	for u in self.names.iter() {
+	    write!(fd,",{}",csv_field(u))?;
+	}
+	writeln!(fd)?;
+	for (i,u) in self.names.iter().enumerate() {
+	    write!(fd,"{}",csv_field(u))?;
+	    for j in 0..self.len() {
+		write!(fd,",{:.6}",self.entry(i,j)/1e-12)?;
+	    }
+	    writeln!(fd)?;
+	}
+	Ok(())
+    }
+}
+
+fn csv_field(u:&str)->String {
+    if u.contains([',','"']) {
+	format!("\"{}\"",u.replace('"',"\"\""))
+    } else {
+	u.to_string()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_maxwell() {
+    let close = |a:Real,b:Real| (a - b).abs() < 1e-18;
+    let mut mx = Maxwell::new(vec!["GND".to_string(),"A".to_string(),"B,C".to_string()]);
+    mx.add_mutual(0,1,2e-12);
+    mx.add_mutual(2,0,3e-12);
+    mx.add_mutual(1,2,0.5e-12);
+    mx.add_mutual(1,0,1e-12);
+    assert_eq!(mx.len(),3);
+    assert_eq!(mx.find("B,C"),Some(2));
+    assert_eq!(mx.find("D"),None);
+    assert!(close(mx.mutual(0,1),3e-12));
+    assert_eq!(mx.mutual(0,1),mx.mutual(1,0));
+    assert_eq!(mx.mutual(1,1),0.0);
+    assert!(close(mx.entry(2,1),-0.5e-12));
+    assert!(close(mx.total(0),6e-12));
+    assert!(close(mx.total(1),3.5e-12));
+    assert!(close(mx.to(1,|u| u == "GND"),3e-12));
+    assert!(close(mx.to(1,|_| true),mx.total(1)));
+    // Missing couplings are +0, not -0
+    assert!(mx.to(0,|u| u == "GND").is_sign_positive());
+    let mx = Maxwell::new(vec!["X".to_string(),"Y".to_string()]);
+    assert!(mx.entry(0,1).is_sign_positive());
+    assert_eq!(csv_field("B,C"),"\"B,C\"");
+    assert_eq!(csv_field("B\"C"),"\"B\"\"C\"");
+}
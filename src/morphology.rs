@@ -0,0 +1,158 @@
+use crate::math::*;
+
+const FAR : Real = 1e20;
+
+/// One-dimensional squared distance transform of sampled function f
+/// into d, using the lower envelope of parabolas (Felzenszwalb and
+/// Huttenlocher); v and z are work areas of sizes n and n + 1
+fn edt_1d(f:&[Real],d:&mut [Real],v:&mut [usize],z:&mut [Real]) {
+    let n = f.len();
+    let mut k = 0;
+    v[0] = 0;
+    z[0] = -FAR;
+    z[1] = FAR;
+    for q in 1..n {
+	loop {
+	    let p = v[k];
+	    let s = ((f[q] + (q*q) as Real) - (f[p] + (p*p) as Real))
+		/ (2.0 * (q as Real - p as Real));
+	    if s <= z[k] && k > 0 {
+		k -= 1;
+	    } else {
+		k += 1;
+		v[k] = q;
+		z[k] = s;
+		z[k + 1] = FAR;
+		break;
+	    }
+	}
+    }
+    k = 0;
+    for (q,dq) in d.iter_mut().enumerate().take(n) {
+	while z[k + 1] < q as Real {
+	    k += 1;
+	}
+	let p = v[k];
+	*dq = sq(q as Real - p as Real) + f[p];
+    }
+}
+
+/// Squared Euclidean distance, in pixels, from each pixel to the
+/// nearest pixel where the predicate holds
+pub fn edt<F:Fn(u16)->bool>(a:&Array2<u16>,pred:F)->Array2<Real> {
+    let (ny,nx) = a.dim();
+    let n = ny.max(nx);
+    let mut f = vec![0.0;n];
+    let mut d = vec![0.0;n];
+    let mut v = vec![0;n];
+    let mut z = vec![0.0;n + 1];
+    let mut g = a.mapv(|x| if pred(x) { 0.0 } else { FAR });
+    for ix in 0..nx {
+	for iy in 0..ny {
+	    f[iy] = g[[iy,ix]];
+	}
+	edt_1d(&f[0..ny],&mut d[0..ny],&mut v,&mut z);
+	for iy in 0..ny {
+	    g[[iy,ix]] = d[iy];
+	}
+    }
+    for iy in 0..ny {
+	for ix in 0..nx {
+	    f[ix] = g[[iy,ix]];
+	}
+	edt_1d(&f[0..nx],&mut d[0..nx],&mut v,&mut z);
+	for ix in 0..nx {
+	    g[[iy,ix]] = d[ix];
+	}
+    }
+    g
+}
+
+/// Squared distance transform to the pixels having the mask, or
+/// lacking it, the area outside of the bitmap lacking it
+fn distance(a:&Array2<u16>,mask:u16,to_mask:bool)->Array2<Real> {
+    if to_mask {
+	return edt(a,|x| x & mask != 0);
+    }
+    let (ny,nx) = a.dim();
+    let mut padded = Array2::zeros((ny + 2,nx + 2));
+    padded.slice_mut(s![1..ny + 1,1..nx + 1]).assign(a);
+    edt(&padded,|x| x & mask == 0).slice(s![1..ny + 1,1..nx + 1]).to_owned()
+}
+
+/// Grows (r > 0) or shrinks (r < 0) the pixels having the given mask
+/// by |r| pixels, measured between pixel centers, the area outside of
+/// the bitmap being bare.  With a coverage, added pixels get full
+/// coverage and the fractional part of |r| goes to the next ring of
+/// pixels: they are added with that part of full coverage when
+/// growing, and keep the rest of their coverage when shrinking.
+pub fn offset(a:&mut Array2<u16>,mask:u16,r:Real,mut coverage:Option<&mut Array2<u8>>) {
+    let (k,f) = match coverage {
+	Some(_) => (r.abs().floor(),r.abs().fract()),
+	None => (r.abs(),0.0)
+    };
+    if k == 0.0 && f == 0.0 {
+	return;
+    }
+    let (inner,outer) = (k * k,sq(k + 1.0));
+    let grow = r > 0.0;
+    let d = distance(a,mask,grow);
+    ndarray::Zip::indexed(&mut *a).and(&d).for_each(|i,x,&d| {
+	let c = coverage.as_deref_mut().map(|c| &mut c[i]);
+	if grow && *x & mask == 0 {
+	    if d <= inner {
+		*x |= mask;
+		if let Some(c) = c {
+		    *c = 255;
+		}
+	    } else if f > 0.0 && d <= outer {
+		*x |= mask;
+		if let Some(c) = c {
+		    *c = (255.0 * f).round().max(1.0) as u8;
+		}
+	    }
+	} else if !grow && *x & mask != 0 {
+	    if d <= inner {
+		*x &= !mask;
+		if let Some(c) = c {
+		    *c = 0;
+		}
+	    } else if f > 0.0 && d <= outer {
+		if let Some(c) = c {
+		    *c = (*c as Real * (1.0 - f)).round() as u8;
+		}
+	    }
+	}
+    });
+}
+
+#[cfg(test)]
+#[test]
+fn test_offset() {
+    // A 4 by 4 square in the corner of an 8 by 8 bitmap
+    let square = || Array2::from_shape_fn((8,8),|(iy,ix)| (iy < 4 && ix < 4) as u16);
+    let count = |a:&Array2<u16>| a.iter().filter(|&&x| x != 0).count();
+
+    // Shrinking erodes the edges of the bitmap too
+    let mut a = square();
+    offset(&mut a,1,-1.0,None);
+    assert_eq!(count(&a),4);
+    assert!(a[[1,1]] == 1 && a[[0,0]] == 0);
+
+    let mut a = square();
+    offset(&mut a,1,1.0,None);
+    assert_eq!(count(&a),24);
+
+    // Fractional parts go to the coverage of the next ring
+    let mut a = square();
+    let mut c = a.mapv(|x| 255 * x as u8);
+    offset(&mut a,1,0.4,Some(&mut c));
+    assert_eq!(count(&a),24);
+    assert_eq!((c[[3,3]],c[[4,3]],c[[4,4]],c[[3,5]]),(255,102,0,0));
+
+    let mut a = square();
+    let mut c = a.mapv(|x| 255 * x as u8);
+    offset(&mut a,1,-1.4,Some(&mut c));
+    assert_eq!(count(&a),4);
+    assert_eq!((c[[0,0]],c[[1,1]],c[[2,2]]),(0,153,153));
+}
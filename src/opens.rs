@@ -0,0 +1,157 @@
+use log::{info,warn};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Write,BufWriter}
+};
+
+use crate::{
+    common::*,
+    math::*,
+    config::Config,
+    components::UnionFind,
+    drill::Hole,
+    shorts::Hit
+};
+
+/// Components (ilay,icom) joined at each location, by net
+type Joints<'a,K> = BTreeMap<(&'a str,K),Vec<(usize,usize)>>;
+
+/// Components (ilay,icom) hit by each net, with one of their flash
+/// points
+type Nodes<'a> = BTreeMap<&'a str,BTreeMap<(usize,usize),(Real,Real)>>;
+
+/// Components of a net joined through vias, each with one of its
+/// flash points
+type Group = Vec<((usize,usize),(Real,Real))>;
+
+/// Groups of the components hit by each net.  Components of
+/// different layers are joined by flash points of the net at the same
+/// location, such as vias and through-hole pads, and by flash points
+/// reached by the same plated hole.
+fn groups<'a>(hits:&'a [Vec<Hit>],holes:&[Hole])->BTreeMap<&'a str,Vec<Group>> {
+    let key = |x:Real,y:Real| ((x*1e3).round() as i64,(y*1e3).round() as i64);
+    let reach = |h:&Hole| h.via_tolerance.map(|t| 0.5*h.diameter + t);
+    let cell = holes.iter().filter_map(reach).fold(0.0,Real::max);
+    let bucket = |x:Real,y:Real| ((x / cell).floor() as i64,(y / cell).floor() as i64);
+    let mut buckets : BTreeMap<(i64,i64),Vec<usize>> = BTreeMap::new();
+    if cell > 0.0 {
+	for (k,h) in holes.iter().enumerate() {
+	    if reach(h).is_some() {
+		buckets.entry(bucket(h.x,h.y)).or_default().push(k);
+	    }
+	}
+    }
+
+    // Components hit by each net, and by each of its flash point
+    // locations and plated holes
+    let mut nodes : Nodes = BTreeMap::new();
+    let mut locations : Joints<(i64,i64)> = BTreeMap::new();
+    let mut vias : Joints<usize> = BTreeMap::new();
+    for (ilay,hs) in hits.iter().enumerate() {
+	for h in hs.iter() {
+	    nodes.entry(&h.name).or_default()
+		.entry((ilay,h.icom))
+		.or_insert((h.x,h.y));
+	    locations.entry((&h.name,key(h.x,h.y))).or_default()
+		.push((ilay,h.icom));
+	    if buckets.is_empty() {
+		continue;
+	    }
+	    let (bx,by) = bucket(h.x,h.y);
+	    for b in [bx - 1,bx,bx + 1].into_iter()
+		.flat_map(|bx| [(bx,by - 1),(bx,by),(bx,by + 1)]) {
+		for &k in buckets.get(&b).into_iter().flatten() {
+		    let hole = &holes[k];
+		    if reach(hole).is_some_and(|r| hypot(h.x - hole.x,h.y - hole.y) <= r) {
+			vias.entry((&h.name,k)).or_default().push((ilay,h.icom));
+		    }
+		}
+	    }
+	}
+    }
+
+    nodes.into_iter().map(|(name,comps)| {
+	let index : BTreeMap<(usize,usize),u32> = comps.keys()
+	    .enumerate()
+	    .map(|(k,&n)| (n,k as u32 + 1))
+	    .collect();
+	let mut uf = UnionFind::new();
+	for _ in 0..index.len() {
+	    uf.make();
+	}
+	let joined = locations.range((name,(i64::MIN,i64::MIN))..=(name,(i64::MAX,i64::MAX)))
+	    .map(|(_,ns)| ns)
+	    .chain(vias.range((name,0)..=(name,usize::MAX)).map(|(_,ns)| ns));
+	for ns in joined {
+	    for w in ns.windows(2) {
+		uf.union(index[&w[0]],index[&w[1]]);
+	    }
+	}
+	let mut groups : BTreeMap<u32,Group> = BTreeMap::new();
+	for (&n,&l) in index.iter() {
+	    groups.entry(uf.find(l)).or_default().push((n,comps[&n]));
+	}
+	(name,groups.into_values().collect())
+    }).collect()
+}
+
+/// Finds nets whose copper, as reached by their flash points, forms
+/// several groups of components that no via joins.  The groups are
+/// written to opens.txt.
+pub fn check(config:&Config,hits:&[Vec<Hit>],holes:&[Hole])->Res<()> {
+    let opens_path = format!("{}/opens.txt",config.output);
+    let fd = File::create(&opens_path)?;
+    let mut fd = BufWriter::new(fd);
+    writeln!(fd,"# Nets whose copper forms groups not joined through vias; \
+		  each group lists layer:component and a flash point in mm")?;
+    let mut n_open = 0;
+    let mut n_split = 0;
+    for (name,groups) in groups(hits,holes).iter() {
+	if groups.len() > 1 {
+	    n_open += 1;
+	    writeln!(fd,"{} {} groups",name,groups.len())?;
+	    for g in groups.iter() {
+		write!(fd," ")?;
+		for &((ilay,icom),_) in g.iter() {
+		    write!(fd," {}:{}",config.layers[ilay].name,icom + 1)?;
+		}
+		let (x,y) = g[0].1;
+		writeln!(fd," at {},{}",x,y)?;
+	    }
+	} else if groups[0].windows(2).any(|w| w[0].0.0 == w[1].0.0) {
+	    n_split += 1;
+	}
+    }
+    info!("{} nets split on a layer but joined through vias",n_split);
+    if n_open > 0 {
+	warn!("{} nets have copper not joined through vias, see {}",
+	      n_open,opens_path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_groups() {
+    let hit = |icom:usize,name:&str,x:Real,y:Real| Hit { icom,name:name.to_string(),x,y,rank:0 };
+    // A's pad on the top layer and its via landing 0.1 mm away on the
+    // bottom layer; B's pads at the same location on both layers
+    let hits = vec![
+	vec![hit(0,"A",1.0,1.0),hit(1,"B",5.0,5.0)],
+	vec![hit(3,"A",1.1,1.0),hit(2,"B",5.0,5.0)]
+    ];
+    let hole = |x:Real,via_tolerance:Option<Real>|
+	Hole { x,y:1.0,diameter:0.15,via_tolerance };
+    let count = |holes:&[Hole]| groups(&hits,holes)
+	.into_iter()
+	.map(|(name,gs)| (name,gs.len()))
+	.collect::<Vec<_>>();
+
+    assert_eq!(count(&[]),vec![("A",2),("B",1)]);
+    // Unplated hole, hole too small, hole reaching both points
+    assert_eq!(count(&[hole(1.05,None)]),vec![("A",2),("B",1)]);
+    assert_eq!(count(&[hole(1.0,Some(0.01))]),vec![("A",2),("B",1)]);
+    assert_eq!(count(&[hole(1.05,Some(0.0)),hole(9.0,Some(0.5))]),vec![("A",1),("B",1)]);
+    assert_eq!(count(&[hole(1.0,Some(0.05))]),vec![("A",1),("B",1)]);
+}
@@ -0,0 +1,492 @@
+use std::f64::consts::SQRT_2;
+use log::{info,warn};
+use rayon::prelude::*;
+use ndarray_rand::rand::Rng;
+
+use crate::{
+    common::*,
+    math::*,
+    math_random::Random,
+    spherical::sample_spherical,
+    morphology,
+    config::{Config,RandomWalk}
+};
+
+const EPS0 : Real = 8.854e-12;
+
+/// Walks run by each task of a round
+const WALKS_PER_BATCH : usize = 1000;
+
+/// Steps after which a walk is given up and counted as reaching the
+/// ground
+const MAX_STEPS : usize = 100_000;
+
+/// Distance to a surface, relative to the pixel size, at which a
+/// walk is snapped onto it
+const SHELL : Real = 1e-3;
+
+/// Distance in subpixels up to which the Gaussian surface around a
+/// conductor extends
+const SURFACE_RADIUS : usize = 4;
+
+/// Distance transforms are exact between pixel centers; below this
+/// many pixels the distance to copper is computed exactly
+const EXACT_DISTANCE : Real = 3.0;
+
+/// Copper planes between dielectric slabs, with grounded planes at the
+/// top and bottom and mirror symmetry at the edges of the bitmaps.
+/// Lengths are in millimeters and z increases upwards.
+struct Domain<'a> {
+    delta:Real,
+    width:Real,
+    height:Real,
+    shell:Real,
+    n:usize,
+    conductors:&'a [Array2<Option<usize>>],
+    /// Distance in pixels between the center of each pixel and the
+    /// center of the nearest copper pixel, per layer
+    distance:Vec<Array2<Real>>,
+    /// Heights of the top ground, of the layers and of the bottom
+    /// ground
+    z:Vec<Real>,
+    /// Relative permittivity of the slabs between these
+    eps:Vec<Real>
+}
+
+enum Hop {
+    Absorbed(usize),
+    Sphere(Real,Real3)
+}
+
+/// Cells of a Gaussian surface having the same orientation and weight
+struct Patches {
+    /// Height of the layer
+    z:Real,
+    /// Distance of the faces to the layer
+    gap:Real,
+    normal:Real3,
+    /// Permittivity times area of each cell, in square millimeters
+    weight:Real,
+    cells:Vec<(u32,u32)>
+}
+
+/// Closed surface around the copper of a conductor, made of faces
+/// above and below each layer over the subpixels closer to that
+/// conductor than to any other copper, and of the band across the
+/// layer at their rim
+struct Surface {
+    patches:Vec<Patches>,
+    cumulative:Vec<Real>,
+    total:Real
+}
+
+#[derive(Clone)]
+struct Stats {
+    /// Walks entering the estimates
+    walks:usize,
+    /// Walks left out for starting within the shell of copper
+    touching:usize,
+    /// Walks left out for being given up
+    lost:usize,
+    sum:Vec<Real>,
+    sum_sq:Vec<Real>
+}
+
+impl Stats {
+    fn new(n:usize)->Self {
+	Self { walks:0, touching:0, lost:0, sum:vec![0.0;n + 1], sum_sq:vec![0.0;n + 1] }
+    }
+
+    fn add(&mut self,k:usize,x:Real) {
+	self.sum[k] += x;
+	self.sum_sq[k] += x * x;
+    }
+
+    fn merge(&mut self,other:&Stats) {
+	self.walks += other.walks;
+	self.touching += other.touching;
+	self.lost += other.lost;
+	for k in 0..self.sum.len() {
+	    self.sum[k] += other.sum[k];
+	    self.sum_sq[k] += other.sum_sq[k];
+	}
+    }
+
+    fn attempts(&self)->usize {
+	self.walks + self.touching + self.lost
+    }
+
+    fn mean(&self,k:usize)->Real {
+	self.sum[k] / self.walks as Real
+    }
+
+    /// Half-width of the confidence interval of the mean for the
+    /// given normal quantile
+    fn half_width(&self,k:usize,z:Real)->Real {
+	let w = self.walks as Real;
+	let m = self.mean(k);
+	let var = ((self.sum_sq[k] / w - m * m) * w / (w - 1.0)).max(0.0);
+	z * sqrt(var / w)
+    }
+}
+
+/// Folds a coordinate into [0,l] by reflection at both ends
+fn fold(v:Real,l:Real)->Real {
+    let v = v.rem_euclid(2.0 * l);
+    if v > l { 2.0 * l - v } else { v }
+}
+
+/// Quantile of the standard normal distribution for 0.5 < p < 1
+/// (Abramowitz and Stegun 26.2.23, error below 4.5e-4)
+fn normal_quantile(p:Real)->Real {
+    let t = sqrt(-2.0 * log(1.0 - p));
+    t - (2.515517 + 0.802853 * t + 0.010328 * t * t)
+	/ (1.0 + 1.432788 * t + 0.189269 * t * t + 0.001308 * t * t * t)
+}
+
+impl<'a> Domain<'a> {
+    fn new(config:&Config,rw:&RandomWalk,delta:Real,
+	   conductors:&'a [Array2<Option<usize>>],n:usize)->Self {
+	let nlay = conductors.len();
+	let (ny,nx) = conductors[0].dim();
+	let mut z = vec![rw.margin,0.0];
+	let mut eps = vec![1.0];
+	for ilay in 0..nlay - 1 {
+	    let d = config.dielectric(ilay);
+	    z.push(z[ilay + 1] - d.thickness);
+	    eps.push(d.eps_rel);
+	}
+	z.push(z[nlay] - rw.margin);
+	eps.push(1.0);
+	let distance = conductors.iter()
+	    .map(|c| {
+		let a = c.mapv(|k| k.is_some() as u16);
+		morphology::edt(&a,|x| x != 0).mapv(sqrt)
+	    })
+	    .collect();
+	Self {
+	    delta,
+	    width:nx as Real * delta,
+	    height:ny as Real * delta,
+	    shell:SHELL * delta,
+	    n,
+	    conductors,
+	    distance,
+	    z,
+	    eps
+	}
+    }
+
+    fn pixel(&self,x:Real,y:Real)->(usize,usize) {
+	let (ny,nx) = self.conductors[0].dim();
+	(((y / self.delta) as usize).min(ny - 1),((x / self.delta) as usize).min(nx - 1))
+    }
+
+    /// Lower bound of the distance from a point of a layer to its
+    /// copper, exact near the copper where the nearest conductor is
+    /// also given
+    fn copper_distance(&self,ilay:usize,x:Real,y:Real)->(Real,Option<usize>) {
+	let (iy,ix) = self.pixel(x,y);
+	let e = self.distance[ilay][[iy,ix]];
+	if e >= EXACT_DISTANCE {
+	    return ((e - SQRT_2) * self.delta,None);
+	}
+	let cond = &self.conductors[ilay];
+	let (ny,nx) = cond.dim();
+	let r = EXACT_DISTANCE as usize + 2;
+	let mut best = (Real::INFINITY,None);
+	for jy in iy.saturating_sub(r)..(iy + r + 1).min(ny) {
+	    for jx in ix.saturating_sub(r)..(ix + r + 1).min(nx) {
+		if let Some(k) = cond[[jy,jx]] {
+		    let dx = (jx as Real * self.delta - x).max(x - (jx + 1) as Real * self.delta);
+		    let dy = (jy as Real * self.delta - y).max(y - (jy + 1) as Real * self.delta);
+		    let d = hypot(dx.max(0.0),dy.max(0.0));
+		    if d < best.0 {
+			best = (d,Some(k));
+		    }
+		}
+	    }
+	}
+	best
+    }
+
+    /// Next sphere of the walk from p, which is folded into the domain
+    /// and snapped onto a layer when close enough.  Spheres centered on
+    /// a layer are restricted to a hemisphere chosen in proportion to
+    /// the permittivities on both sides.
+    fn hop(&self,p:&mut Real3,rng:&mut Random)->Hop {
+	p[0] = fold(p[0],self.width);
+	p[1] = fold(p[1],self.height);
+	let z = &self.z;
+	let m = z.len();
+	if p[2] >= z[0] - self.shell || p[2] <= z[m - 1] + self.shell {
+	    return Hop::Absorbed(self.n);
+	}
+	let k = z.iter().position(|&zk| zk <= p[2]).unwrap() - 1;
+	let (du,dd) = (z[k] - p[2],p[2] - z[k + 1]);
+	let s = if du <= self.shell {
+	    k
+	} else if dd <= self.shell {
+	    k + 1
+	} else {
+	    return Hop::Sphere(du.min(dd),sample_spherical(rng));
+	};
+	if s == 0 || s == m - 1 {
+	    return Hop::Absorbed(self.n);
+	}
+	p[2] = z[s];
+	let ilay = s - 1;
+	let (iy,ix) = self.pixel(p[0],p[1]);
+	if let Some(k) = self.conductors[ilay][[iy,ix]] {
+	    return Hop::Absorbed(k);
+	}
+	let (dc,nearest) = self.copper_distance(ilay,p[0],p[1]);
+	if dc <= self.shell {
+	    return Hop::Absorbed(nearest.unwrap_or(self.n));
+	}
+	let r = dc.min(z[s - 1] - z[s]).min(z[s] - z[s + 1]);
+	let (ea,eb) = (self.eps[s - 1],self.eps[s]);
+	let mut u = sample_spherical(rng);
+	let up = rng.number(0.0,ea + eb) < ea;
+	if (u[2] > 0.0) != up {
+	    u[2] = -u[2];
+	}
+	Hop::Sphere(r,u)
+    }
+
+    /// Conductor where a walk from p ends, or None if it was given up
+    fn walk(&self,mut p:Real3,rng:&mut Random)->Option<usize> {
+	for _ in 0..MAX_STEPS {
+	    match self.hop(&mut p,rng) {
+		Hop::Absorbed(k) => return Some(k),
+		Hop::Sphere(r,u) => p = p + r * u
+	    }
+	}
+	None
+    }
+
+    fn surface(&self,i:usize)->Surface {
+	let r = SURFACE_RADIUS;
+	let s = 0.5 * self.delta;
+	let mut patches = Vec::new();
+	for (ilay,cond) in self.conductors.iter().enumerate() {
+	    let (ny,nx) = cond.dim();
+	    let mut bbox : Option<(usize,usize,usize,usize)> = None;
+	    for ((iy,ix),&k) in cond.indexed_iter() {
+		if k == Some(i) {
+		    bbox = Some(match bbox {
+			None => (iy,ix,iy,ix),
+			Some((y0,x0,y1,x1)) => (y0.min(iy),x0.min(ix),y1.max(iy),x1.max(ix))
+		    });
+		}
+	    }
+	    let Some((y0,x0,y1,x1)) = bbox else { continue };
+
+	    // Subpixels within reach, marked 1 for the conductor and 2
+	    // for other copper
+	    let sy0 = (2 * y0).saturating_sub(2 * r + 1);
+	    let sx0 = (2 * x0).saturating_sub(2 * r + 1);
+	    let sy1 = (2 * y1 + 2 + 2 * r + 1).min(2 * ny);
+	    let sx1 = (2 * x1 + 2 + 2 * r + 1).min(2 * nx);
+	    let a = Array2::from_shape_fn((sy1 - sy0,sx1 - sx0),|(sy,sx)| {
+		match cond[[(sy0 + sy) / 2,(sx0 + sx) / 2]] {
+		    Some(k) if k == i => 1,
+		    Some(_) => 2,
+		    None => 0
+		}
+	    });
+	    let df = morphology::edt(&a,|x| x == 1);
+	    let dof = morphology::edt(&a,|x| x == 2);
+	    let inside = |sy:isize,sx:isize| {
+		0 <= sy && sy < a.nrows() as isize && 0 <= sx && sx < a.ncols() as isize && {
+		    let (sy,sx) = (sy as usize,sx as usize);
+		    df[[sy,sx]] <= (r * r) as Real && df[[sy,sx]] < dof[[sy,sx]]
+		}
+	    };
+
+	    let zl = self.z[ilay + 1];
+	    let (ea,eb) = (self.eps[ilay],self.eps[ilay + 1]);
+	    let gap = s.min(0.25 * (self.z[ilay] - zl).min(zl - self.z[ilay + 2]));
+	    let mut faces = Vec::new();
+	    let mut rims = vec![Vec::new();4];
+	    let dirs = [(0,1),(0,-1),(1,0),(-1,0)];
+	    for ((sy,sx),&v) in a.indexed_iter() {
+		let (isy,isx) = (sy as isize,sx as isize);
+		if !inside(isy,isx) {
+		    continue;
+		}
+		let cell = ((sy0 + sy) as u32,(sx0 + sx) as u32);
+		faces.push(cell);
+		for (d,&(dy,dx)) in dirs.iter().enumerate() {
+		    let (gy,gx) = ((sy0 + sy) as isize + dy,(sx0 + sx) as isize + dx);
+		    if gy < 0 || gy >= 2 * ny as isize || gx < 0 || gx >= 2 * nx as isize
+			|| inside(isy + dy,isx + dx) || v != 0 {
+			continue;
+		    }
+		    rims[d].push(cell);
+		}
+	    }
+	    let mut add = |normal:Real3,weight:Real,cells:Vec<(u32,u32)>| {
+		if !cells.is_empty() {
+		    patches.push(Patches { z:zl, gap, normal, weight, cells });
+		}
+	    };
+	    add(R3Z,ea * s * s,faces.clone());
+	    add(-R3Z,eb * s * s,faces);
+	    for (d,cells) in rims.into_iter().enumerate() {
+		let (dy,dx) = dirs[d];
+		add(r3(dx as Real,dy as Real,0.0),(ea + eb) * gap * s,cells);
+	    }
+	}
+	let mut total = 0.0;
+	let cumulative = patches.iter()
+	    .map(|p| {
+		total += p.weight * p.cells.len() as Real;
+		total
+	    })
+	    .collect();
+	Surface { patches, cumulative, total }
+    }
+
+    /// Point of the surface drawn in proportion to permittivity times
+    /// area, with the outward normal
+    fn sample(&self,surface:&Surface,rng:&mut Random)->(Real3,Real3) {
+	let w = rng.number(0.0,surface.total);
+	let ip = surface.cumulative.partition_point(|&c| c <= w).min(surface.patches.len() - 1);
+	let p = &surface.patches[ip];
+	let (sy,sx) = p.cells[rng.integer(p.cells.len()).min(p.cells.len() - 1)];
+	let s = 0.5 * self.delta;
+	let n = p.normal;
+	let x = (sx as Real + 0.5 + 0.5 * n[0] + (rng.number(0.0,1.0) - 0.5) * (1.0 - n[0].abs())) * s;
+	let y = (sy as Real + 0.5 + 0.5 * n[1] + (rng.number(0.0,1.0) - 0.5) * (1.0 - n[1].abs())) * s;
+	(r3(x,y,p.z + p.gap * n[2]),n)
+    }
+
+    /// Walks from the surface around conductor i.  The flux through
+    /// the starting point is estimated from the first sphere; the total
+    /// capacitance uses the potential minus one, which has the same
+    /// expectation and a much smaller variance.
+    fn batch(&self,surface:&Surface,i:usize,seed:u64)->Stats {
+	let mut rng = Random::from_seed(seed);
+	let mut stats = Stats::new(self.n);
+	for _ in 0..WALKS_PER_BATCH {
+	    let (mut p,normal) = self.sample(surface,&mut rng);
+	    // A start within the shell of copper, where the surface
+	    // touches another conductor, has no sphere to estimate the
+	    // flux from.  Counting it as zero would bias the mutual
+	    // capacitance to that conductor low, so it is left out,
+	    // which only drops the thin part of the surface next to
+	    // copper.
+	    let Hop::Sphere(r,u) = self.hop(&mut p,&mut rng) else {
+		stats.touching += 1;
+		continue;
+	    };
+	    let w = EPS0 * 1e-3 * surface.total * 3.0 / r * normal.dot(u);
+	    // Walks given up are left out too, rather than credited to
+	    // the ground
+	    let Some(k) = self.walk(p + r * u,&mut rng) else {
+		stats.lost += 1;
+		continue;
+	    };
+	    stats.walks += 1;
+	    if k != i {
+		stats.add(i,w);
+		stats.add(k,-w);
+	    }
+	}
+	stats
+    }
+}
+
+/// Maxwell capacitance matrix, in farads, of conductors 0..n given
+/// per pixel of each layer, with the half-widths of its confidence
+/// intervals, by floating random walks.  Copper of conductor n is
+/// grounded along with planes at the top and bottom of the board.
+pub fn solve(config:&Config,rw:&RandomWalk,delta:Real,
+	     conductors:&[Array2<Option<usize>>],n:usize)
+	     ->Res<(Array2<Real>,Array2<Real>)> {
+    if conductors.is_empty() {
+	return Err(error("No layers for random walk extraction"));
+    }
+    if !(0.0 < rw.confidence && rw.confidence < 1.0) || rw.error <= 0.0 || rw.margin <= 0.0 {
+	return Err(error("Random walk confidence must be between 0 and 1, \
+			  error and margin positive"));
+    }
+    let z = normal_quantile(0.5 + 0.5 * rw.confidence);
+    let seed = rw.seed.unwrap_or_else(|| Random::new().rng.gen());
+    let domain = Domain::new(config,rw,delta,conductors,n);
+    let batches = 4 * rayon::current_num_threads() as u64;
+
+    let mut c = Array2::zeros((n,n));
+    let mut hw = Array2::zeros((n,n));
+    for i in 0..n {
+	let surface = domain.surface(i);
+	if surface.patches.is_empty() {
+	    warn!("Conductor {} has no copper",i);
+	    continue;
+	}
+	let mut stats = Stats::new(n);
+	let mut b = 0;
+	loop {
+	    let round : Vec<Stats> = (b..b + batches).into_par_iter()
+		.map(|b| domain.batch(&surface,i,seed
+				      .wrapping_add((i as u64) << 40)
+				      .wrapping_add(b)))
+		.collect();
+	    round.iter().for_each(|s| stats.merge(s));
+	    b += batches;
+	    let (mean,h) = (stats.mean(i),stats.half_width(i,z));
+	    if h <= rw.error * mean.abs() {
+		info!("Conductor {}: {:.3} +- {:.3} pF after {} walks",
+		      i,mean/1e-12,h/1e-12,stats.walks);
+		break;
+	    }
+	    if stats.attempts() >= rw.max_walks {
+		warn!("Conductor {}: {:.3} +- {:.3} pF after {} walks, \
+		       short of the requested error",
+		      i,mean/1e-12,h/1e-12,stats.walks);
+		break;
+	    }
+	}
+	if stats.touching > 0 {
+	    info!("Conductor {}: {} walks left out for starting on copper",i,stats.touching);
+	}
+	if stats.lost > 0 {
+	    warn!("Conductor {}: {} walks given up after {} steps and left out",
+		  i,stats.lost,MAX_STEPS);
+	}
+	for j in 0..n {
+	    c[[i,j]] = stats.mean(j);
+	    hw[[i,j]] = stats.half_width(j,z);
+	}
+    }
+
+    // Both estimates of each mutual capacitance are averaged
+    let cs = 0.5 * (&c + &c.t());
+    let hws = Array2::from_shape_fn((n,n),|(i,j)| {
+	if i == j {
+	    hw[[i,i]]
+	} else {
+	    0.5 * hypot(hw[[i,j]],hw[[j,i]])
+	}
+    });
+    Ok((cs,hws))
+}
+
+#[cfg(test)]
+#[test]
+fn test_plates() {
+    // Two plates 0.05 mm apart covering the bitmaps, which the mirror
+    // symmetry makes infinite: with a fixed seed, the confidence
+    // interval of their mutual capacitance over the 4 mm square
+    // contains the parallel plate value
+    let config : Config = ron::from_str(
+	"(input:\"\",output:\"\",layers:[],roi:None,mark:None,\
+	  origin:(x:0.0,y:0.0),dpi:254.0,eps_rel:4.0,thickness:0.05,cap_min:0.0)").unwrap();
+    let rw = RandomWalk { error:0.01,seed:Some(1),..RandomWalk::default() };
+    let plate = |k:usize| Array2::from_elem((40,40),Some(k));
+    let (c,hw) = solve(&config,&rw,0.1,&[plate(0),plate(1)],2).unwrap();
+    let c0 = EPS0 * 4.0 * 4e-3 * 4e-3 / 0.05e-3;
+    assert!(c[[0,1]] < 0.0 && (-c[[0,1]] - c0).abs() <= hw[[0,1]]);
+    assert_eq!(c[[0,1]],c[[1,0]]);
+}
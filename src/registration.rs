@@ -0,0 +1,261 @@
+use log::info;
+use std::collections::BTreeSet;
+use rayon::prelude::*;
+use nalgebra::{Matrix3,Vector3};
+
+use crate::{
+    common::*,
+    math::*,
+    config,
+    disk::DiskIterator,
+    gerber::NetInfos,
+    grid::Grid
+};
+
+/// Maximum number of flash points used for the exhaustive search
+const SEARCH_POINTS_MAX : usize = 500;
+
+/// Largest number of flash point lookups of the exhaustive search
+const SEARCH_LOOKUPS_MAX : usize = 10_000_000_000;
+
+/// Number of robust least-squares iterations
+const FIT_ITERATIONS : usize = 3;
+
+/// Flash point of a layer
+#[derive(Clone,Debug)]
+pub struct Anchor {
+    pub mask:u16,
+    pub x:Real,
+    pub y:Real
+}
+
+#[derive(Clone,Debug)]
+pub struct Residual {
+    pub x:Real,
+    pub y:Real,
+    pub dx:Real,
+    pub dy:Real,
+    pub inlier:bool
+}
+
+pub struct Registration {
+    pub grid:Grid,
+    pub n_points:usize,
+    pub n_hits:usize,
+    pub rms:Real,
+    pub residuals:Vec<Residual>
+}
+
+impl Registration {
+    pub fn dpi(&self)->Real {
+	25.4 / self.grid.delta
+    }
+}
+
+pub fn anchors(net_infos:&[NetInfos])->Vec<Anchor> {
+    let mut anchors = Vec::new();
+    for (ilay,ni) in net_infos.iter().enumerate() {
+	for points in ni.index.values() {
+	    for p in points.iter() {
+		anchors.push(Anchor { mask:1 << ilay,x:p.x,y:p.y });
+	    }
+	}
+    }
+    anchors
+}
+
+fn hits(layers:&Array2<u16>,grid:&Grid,anchors:&[Anchor])->usize {
+    anchors.iter()
+	.filter(|a| grid.pixel(a.x,a.y)
+		.map(|(iy,ix)| layers[[iy,ix]] & a.mask != 0)
+		.unwrap_or(false))
+	.count()
+}
+
+/// Centroid of the copper connected to a point within a disk
+/// around it, in fractional pixel indices
+pub fn centroid(layers:&Array2<u16>,mask:u16,iyc:Real,ixc:Real,r:Real)
+	    ->Option<(Real,Real)> {
+    let (ny,nx) = layers.dim();
+    let inside : BTreeSet<(isize,isize)> =
+	DiskIterator::new(iyc + 0.5,ixc + 0.5,r,
+			  0,ny as isize - 1,
+			  0,nx as isize - 1)
+	.filter(|&(iy,ix)| layers[[iy as usize,ix as usize]] & mask != 0)
+	.collect();
+    let start = ((iyc + 0.5).floor() as isize,(ixc + 0.5).floor() as isize);
+    if !inside.contains(&start) {
+	return None;
+    }
+    let mut visited = BTreeSet::new();
+    let mut active = vec![start];
+    visited.insert(start);
+    let mut sy = 0.0;
+    let mut sx = 0.0;
+    while let Some((iy,ix)) = active.pop() {
+	sy += iy as Real;
+	sx += ix as Real;
+	for c in [(iy - 1,ix),(iy + 1,ix),(iy,ix - 1),(iy,ix + 1)] {
+	    if inside.contains(&c) && visited.insert(c) {
+		active.push(c);
+	    }
+	}
+    }
+    let n = visited.len() as Real;
+    Some((sy / n,sx / n))
+}
+
+/// Exhaustive search of the origin and resolution maximizing the
+/// number of flash points landing on copper of their layer
+fn search(layers:&Array2<u16>,grid:&Grid,anchors:&[Anchor],
+	  params:&config::Registration)->Res<Grid> {
+    let stride = anchors.len().div_ceil(SEARCH_POINTS_MAX);
+    let sample : Vec<Anchor> = anchors.iter().step_by(stride.max(1))
+	.cloned().collect();
+    let (ny,nx) = layers.dim();
+    let ds = 0.5 / ny.max(nx) as Real;
+    let ns = (params.dpi_tolerance / ds).ceil() as isize;
+    let no = (params.range / grid.delta).ceil() as isize;
+    let lookups = ((2*ns + 1)*(2*no + 1)*(2*no + 1)) as usize * sample.len();
+    info!("Searching {} scales and {} offsets using {} points, {} lookups",
+	  2*ns + 1,(2*no + 1)*(2*no + 1),sample.len(),lookups);
+    if lookups > SEARCH_LOOKUPS_MAX {
+	return Err(error(&format!(
+	    "Registration search would need {} lookups, more than {}; \
+	     reduce range or dpi_tolerance",lookups,SEARCH_LOOKUPS_MAX)));
+    }
+    let (_,best) = (-ns..=ns).into_par_iter()
+	.flat_map(|is| (-no..=no).into_par_iter().map(move |iy| (is,iy)))
+	.map(|(is,iy)| {
+	    let mut best = (0,grid.clone());
+	    for ix in -no..=no {
+		let g = Grid {
+		    x0:grid.x0 + ix as Real * grid.delta,
+		    y0:grid.y0 + iy as Real * grid.delta,
+		    delta:grid.delta * (1.0 + is as Real * ds),
+		    ..grid.clone()
+		};
+		let n = hits(layers,&g,&sample);
+		if n > best.0 {
+		    best = (n,g);
+		}
+	    }
+	    best
+	})
+	.reduce(|| (0,grid.clone()),
+		|a,b| if b.0 > a.0 { b } else { a });
+    Ok(best)
+}
+
+/// Least-squares fit of origin and resolution to pad centroids,
+/// discarding outliers
+fn fit(layers:&Array2<u16>,grid:&Grid,anchors:&[Anchor],radius:Real)
+       ->Res<(Grid,Real,Vec<Residual>)> {
+    let ny = grid.ny as Real;
+    let r = radius / grid.delta;
+    let mut obs = Vec::new();
+    for a in anchors.iter() {
+	if grid.pixel(a.x,a.y).is_some() {
+	    let (iyf,ixf) = grid.index(a.x,a.y);
+	    if let Some((cy,cx)) = centroid(layers,a.mask,iyf,ixf,r) {
+		obs.push((a.x,a.y,cx + 0.5,ny - cy - 0.5));
+	    }
+	}
+    }
+    if obs.len() < 3 {
+	return Err(error("Not enough flash points on copper for registration"));
+    }
+
+    let mut inlier = vec![true;obs.len()];
+    let mut g = grid.clone();
+    let mut rms = 0.0;
+    for _ in 0..FIT_ITERATIONS {
+	let n = inlier.iter().filter(|&&i| i).count();
+	if n < 3 {
+	    return Err(error(&format!(
+		"Only {} of {} flash points left as inliers for registration",
+		n,obs.len())));
+	}
+	let mut a = Matrix3::zeros();
+	let mut b = Vector3::zeros();
+	for (&(x,y,u,v),_) in obs.iter().zip(inlier.iter()).filter(|(_,&i)| i) {
+	    for (row,z) in [(Vector3::new(1.0,0.0,u),x),
+			    (Vector3::new(0.0,1.0,v),y)] {
+		a += row * row.transpose();
+		b += row * z;
+	    }
+	}
+	let p = a.lu().solve(&b)
+	    .ok_or_else(|| error("Singular registration problem"))?;
+	g = Grid { x0:p[0],y0:p[1],delta:p[2],..grid.clone() };
+	let res : Vec<Real> = obs.iter()
+	    .map(|&(x,y,u,v)| hypot(x - p[0] - p[2]*u,y - p[1] - p[2]*v))
+	    .collect();
+	rms = sqrt(res.iter().zip(inlier.iter())
+		   .filter(|(_,&i)| i)
+		   .map(|(&e,_)| sq(e))
+		   .sum::<Real>() / n as Real);
+	let limit = (3.0 * rms).max(g.delta);
+	for (i,&e) in inlier.iter_mut().zip(res.iter()) {
+	    *i = e <= limit;
+	}
+    }
+    let residuals = obs.iter().zip(inlier.iter())
+	.map(|(&(x,y,u,v),&inlier)| Residual {
+	    x,
+	    y,
+	    dx:x - g.x0 - g.delta*u,
+	    dy:y - g.y0 - g.delta*v,
+	    inlier
+	})
+	.collect();
+    Ok((g,rms,residuals))
+}
+
+/// Estimates the origin and resolution of the bitmaps from the
+/// flash points of the Gerber files, starting from the given grid
+pub fn register(layers:&Array2<u16>,grid:&Grid,net_infos:&[NetInfos],
+		params:&config::Registration)->Res<Registration> {
+    let anchors = anchors(net_infos);
+    let n_points = anchors.len();
+    info!("Initial grid: {} of {} flash points on copper",
+	  hits(layers,grid,&anchors),n_points);
+    let g = search(layers,grid,&anchors,params)?;
+    info!("Best search grid: origin ({:.4},{:.4}), dpi {:.3}, {} hits",
+	  g.x0,g.y0,25.4 / g.delta,hits(layers,&g,&anchors));
+    let (g,_,_) = fit(layers,&g,&anchors,params.radius)?;
+    let (g,rms,residuals) = fit(layers,&g,&anchors,params.radius)?;
+    let n_hits = hits(layers,&g,&anchors);
+    Ok(Registration { grid:g,n_points,n_hits,rms,residuals })
+}
+
+#[cfg(test)]
+#[test]
+fn test_fit() {
+    // Square pads of 5 by 5 pixels on a grid with origin (1,2) at 254
+    // dpi, and flash points at their centers
+    let truth = Grid::new(200,300,&config::Point { x:1.0,y:2.0 },254.0);
+    let mut layers = Array2::zeros((200,300));
+    let mut anchors = Vec::new();
+    for iy in (20..200).step_by(40) {
+	for ix in (20..300).step_by(40) {
+	    for jy in iy - 2..=iy + 2 {
+		for jx in ix - 2..=ix + 2 {
+		    layers[[jy,jx]] = 1;
+		}
+	    }
+	    let (x,y) = truth.point(iy,ix);
+	    anchors.push(Anchor { mask:1,x,y });
+	}
+    }
+    assert_eq!(centroid(&layers,1,59.4,100.3,0.5 / 0.1),Some((60.0,100.0)));
+    assert_eq!(centroid(&layers,2,60.0,100.0,5.0),None);
+    assert_eq!(centroid(&layers,1,50.0,100.0,5.0),None);
+
+    let start = Grid { x0:1.04,y0:1.97,delta:0.1 * 1.001,..truth.clone() };
+    let (g,_,_) = fit(&layers,&start,&anchors,0.5).unwrap();
+    let (g,rms,residuals) = fit(&layers,&g,&anchors,0.5).unwrap();
+    assert!((g.x0 - 1.0).abs() < 1e-6 && (g.y0 - 2.0).abs() < 1e-6);
+    assert!((25.4 / g.delta - 254.0).abs() < 1e-4);
+    assert!(rms < 1e-6 && residuals.iter().all(|r| r.inlier));
+}
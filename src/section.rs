@@ -0,0 +1,228 @@
+use log::{info,warn};
+
+use crate::{
+    common::*,
+    math::*,
+    grid::Grid,
+    field,
+    classes::NetClasses,
+    xorwow::Xorwow,
+    config::{Config,CrossSection,Dielectric,FieldSolver}
+};
+
+/// Speed of light in vacuum, in meters per second
+const C0 : Real = 299_792_458.0;
+
+/// Rows of the image across the thinnest dielectric
+const IMAGE_ROWS : Real = 8.0;
+
+/// Copper of the layers sampled along a cut line
+pub struct Section {
+    /// Distance between samples, in millimeters
+    pub step:Real,
+    /// Nets, or aggregated classes, crossed by the line, in order
+    /// along it
+    pub names:Vec<String>,
+    /// Conductor of each sample of each layer, as a single row: the
+    /// position of its net in names, or their number for reference
+    /// nets and copper without a net
+    pub conductors:Vec<Array2<Option<usize>>>
+}
+
+/// Per-unit-length capacitances and characteristic impedances of the
+/// conductors of a section
+pub struct Lines {
+    /// Maxwell capacitance matrix, in farads per meter
+    pub c:Array2<Real>,
+    /// Same with all dielectrics replaced by air
+    pub c_air:Array2<Real>
+}
+
+impl Lines {
+    /// Characteristic impedance of line i with all other conductors
+    /// grounded, in ohms
+    pub fn z0(&self,i:usize)->Real {
+	1.0 / (C0 * sqrt(self.c[[i,i]] * self.c_air[[i,i]]))
+    }
+
+    /// Effective relative permittivity of line i, the ratio of its
+    /// capacitance to its capacitance in air
+    pub fn eps_eff(&self,i:usize)->Real {
+	self.c[[i,i]] / self.c_air[[i,i]]
+    }
+
+    /// Propagation delay, in picoseconds per millimeter
+    pub fn delay(&self,i:usize)->Real {
+	sqrt(self.eps_eff(i)) / C0 * 1e9
+    }
+}
+
+impl Section {
+    pub fn new(cs:&CrossSection,grid:&Grid,labels:&[Array2<u32>],
+	       names:&[Vec<Option<String>>],classes:&NetClasses)->Res<Self> {
+	let length = hypot(cs.to.x - cs.from.x,cs.to.y - cs.from.y);
+	if length <= 0.0 {
+	    return Err(error("The cut line has zero length"));
+	}
+	let ns = (length / cs.step.unwrap_or(grid.delta)).ceil().max(1.0) as usize;
+	let step = length / ns as Real;
+	info!("Sampling {} layers along a {:.3} mm cut line every {:.4} mm",
+	      labels.len(),length,step);
+
+	let mut section_names : Vec<String> = Vec::new();
+	let mut samples = vec![vec![None;labels.len()];ns];
+	let mut outside = 0;
+	for (k,column) in samples.iter_mut().enumerate() {
+	    let t = (k as Real + 0.5) / ns as Real;
+	    let x = cs.from.x + t * (cs.to.x - cs.from.x);
+	    let y = cs.from.y + t * (cs.to.y - cs.from.y);
+	    let Some((iy,ix)) = grid.pixel(x,y) else {
+		outside += 1;
+		continue;
+	    };
+	    for (ilay,l) in labels.iter().enumerate() {
+		let l = l[[iy,ix]];
+		if l == 0 {
+		    continue;
+		}
+		let net = names[ilay][l as usize - 1].as_deref()
+		    .and_then(|u| classes.conductor(u))
+		    .filter(|u| !classes.is_reference(u));
+		column[ilay] = Some(net.map(|u| {
+		    match section_names.iter().position(|v| *v == u) {
+			Some(i) => i,
+			None => {
+			    section_names.push(u);
+			    section_names.len() - 1
+			}
+		    }
+		}));
+	    }
+	}
+	if outside > 0 {
+	    warn!("{} of {} samples of the cut line fall outside of the bitmaps \
+		   and are left without copper",outside,ns);
+	}
+	let n = section_names.len();
+	let conductors = (0..labels.len())
+	    .map(|ilay| Array2::from_shape_fn((1,ns),|(_,k)| samples[k][ilay].map(|i| i.unwrap_or(n))))
+	    .collect();
+	Ok(Self { step, names:section_names, conductors })
+    }
+
+    /// Solves the section with and without the dielectrics
+    pub fn solve(&self,config:&Config,cs:&CrossSection)->Res<Lines> {
+	let n = self.names.len();
+	let fs = FieldSolver {
+	    nets:self.names.clone(),
+	    cell:None,
+	    z_cells:cs.z_cells,
+	    margin:cs.margin,
+	    tolerance:cs.tolerance,
+	    max_iterations:cs.max_iterations,
+	    ..FieldSolver::default()
+	};
+	let mut air = config.clone();
+	air.stackup = (0..self.conductors.len().saturating_sub(1))
+	    .map(|ilay| Dielectric { eps_rel:1.0,..config.dielectric(ilay) })
+	    .collect();
+	let per_meter = 1.0 / (self.step * 1e-3);
+	info!("Solving the cross-section for {} conductors",n);
+	let c = field::solve(config,&fs,self.step,&self.conductors,n)? * per_meter;
+	info!("Solving the cross-section in air");
+	let c_air = field::solve(&air,&fs,self.step,&self.conductors,n)? * per_meter;
+	Ok(Lines { c, c_air })
+    }
+
+    /// Drawing of the section, with a vertical scale giving each
+    /// dielectric several rows.  Conductors get a color each, other
+    /// copper is gray, dielectrics darken with their permittivity and
+    /// the grounded planes bounding the solution are black.
+    pub fn image(&self,config:&Config,cs:&CrossSection)->Array3<u8> {
+	let nlay = self.conductors.len();
+	let ns = self.conductors[0].ncols();
+	let dielectrics : Vec<Dielectric> = (0..nlay.saturating_sub(1))
+	    .map(|ilay| config.dielectric(ilay))
+	    .collect();
+	let dz = dielectrics.iter()
+	    .map(|d| d.thickness / IMAGE_ROWS)
+	    .fold(self.step,Real::min);
+	let rows = |h:Real| ((h / dz).round() as usize).max(1);
+	let shade = |eps:Real| {
+	    let s = 1.0 / (1.0 + 0.2 * (eps - 1.0));
+	    [(200.0 * s) as u8,(230.0 * s) as u8,(160.0 * s) as u8]
+	};
+
+	// Rows of the drawing: a filling color, or a layer
+	let mut bands : Vec<Result<[u8;3],usize>> = vec![Ok([0,0,0])];
+	bands.extend(std::iter::repeat_n(Ok([255,255,255]),rows(cs.margin)));
+	for ilay in 0..nlay {
+	    bands.push(Err(ilay));
+	    if let Some(d) = dielectrics.get(ilay) {
+		bands.extend(std::iter::repeat_n(Ok(shade(d.eps_rel)),rows(d.thickness)));
+	    }
+	}
+	bands.extend(std::iter::repeat_n(Ok([255,255,255]),rows(cs.margin)));
+	bands.push(Ok([0,0,0]));
+
+	let n = self.names.len();
+	let mut xw = Xorwow::new(1);
+	let mut palette : Vec<[u8;3]> = (0..n)
+	    .map(|_| {
+		let x = xw.next();
+		[((x >> 16) & 255) as u8,((x >> 8) & 255) as u8,(x & 255) as u8]
+	    })
+	    .collect();
+	palette.push([96,96,96]);
+
+	let mut img = Array3::zeros((bands.len(),ns,3));
+	for (row,band) in bands.iter().enumerate() {
+	    for k in 0..ns {
+		let color = match *band {
+		    Ok(color) => color,
+		    Err(ilay) => match self.conductors[ilay][[0,k]] {
+			Some(i) => palette[i],
+			None => bands[row + 1].unwrap_or([255,255,255])
+		    }
+		};
+		for c in 0..3 {
+		    img[[row,k,c]] = color[c];
+		}
+	    }
+	}
+	img
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_stripline() {
+    // A 0.2 mm strip midway between ground planes 0.4 mm apart in a
+    // dielectric of permittivity 4: Cohn's exact impedance for a thin
+    // strip is 30 pi / sqrt(eps) K(k) / K(k') with k = sech(pi w / 2 b)
+    let config : Config = ron::from_str(
+	"(input:\"\",output:\"\",layers:[],roi:None,mark:None,\
+	  origin:(x:0.0,y:0.0),dpi:254.0,eps_rel:4.0,thickness:0.2,cap_min:0.0)").unwrap();
+    let cs = CrossSection::default();
+    let (ns,step) = (400,0.025);
+    let plane = Array2::from_elem((1,ns),Some(1));
+    let strip = Array2::from_shape_fn((1,ns),|(_,k)| (196..204).contains(&k).then_some(0));
+    let section = Section {
+	step,
+	names:vec!["S".to_string()],
+	conductors:vec![plane.clone(),strip,plane]
+    };
+    let lines = section.solve(&config,&cs).unwrap();
+
+    let elliptic_k = |k:Real| {
+	let (mut a,mut b) = (1.0,sqrt(1.0 - k * k));
+	while (a - b).abs() > 1e-15 {
+	    (a,b) = (0.5 * (a + b),sqrt(a * b));
+	}
+	std::f64::consts::PI / (2.0 * a)
+    };
+    let k = 1.0 / (std::f64::consts::PI * 0.2 / (2.0 * 0.4)).cosh();
+    let z0 = 30.0 * std::f64::consts::PI / 2.0 * elliptic_k(k) / elliptic_k(sqrt(1.0 - k * k));
+    assert!((lines.z0(0) / z0 - 1.0).abs() < 0.03);
+    assert!((lines.eps_eff(0) / 4.0 - 1.0).abs() < 1e-3);
+}
@@ -0,0 +1,114 @@
+use log::warn;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Write,BufWriter}
+};
+
+use crate::{
+    common::*,
+    math::*,
+    config::ShortPolicy
+};
+
+/// Flash point of a net landing on a component
+pub struct Hit {
+    pub icom:usize,
+    pub name:String,
+    pub x:Real,
+    pub y:Real,
+    /// Rank of the flash point in the Gerber file
+    pub rank:usize
+}
+
+/// Nets landing on a component, with their flash points
+type Nets<'a> = Vec<(&'a str,Vec<(Real,Real)>)>;
+
+/// Names the m components of a layer from the flash points landing
+/// on them.  Components hit by several nets are named according to
+/// the policy and listed in the report written to path.  Returns the
+/// names and the number of such components.  Nets are taken in the
+/// order of their first flash point on the component in the Gerber
+/// file.
+pub fn resolve(m:usize,hits:&[Hit],policy:ShortPolicy,path:&str)
+	       ->Res<(Vec<Option<String>>,usize)> {
+    let mut sorted : Vec<&Hit> = hits.iter().collect();
+    sorted.sort_by_key(|h| h.rank);
+    let mut per_com : BTreeMap<usize,Nets> = BTreeMap::new();
+    for h in sorted {
+	let nets = per_com.entry(h.icom).or_default();
+	match nets.iter_mut().find(|(name,_)| *name == h.name) {
+	    Some((_,points)) => points.push((h.x,h.y)),
+	    None => nets.push((&h.name,vec![(h.x,h.y)]))
+	}
+    }
+
+    let mut names = vec![None;m];
+    let fd = File::create(path)?;
+    let mut fd = BufWriter::new(fd);
+    writeln!(fd,"# Components matched to several nets, with the flash \
+		  points of each net in mm")?;
+    let mut n_shorts = 0;
+    for (&icom,nets) in per_com.iter() {
+	let name = if nets.len() == 1 || policy != ShortPolicy::Ambiguous {
+	    nets[0].0.to_string()
+	} else {
+	    let mut u : Vec<&str> = nets.iter().map(|&(name,_)| name).collect();
+	    u.sort();
+	    u.join("|")
+	};
+	if nets.len() > 1 {
+	    n_shorts += 1;
+	    writeln!(fd,"component {} named {}",icom + 1,name)?;
+	    for (net,points) in nets.iter() {
+		write!(fd,"  {}",net)?;
+		for (x,y) in points.iter() {
+		    write!(fd," {},{}",x,y)?;
+		}
+		writeln!(fd)?;
+	    }
+	}
+	names[icom] = Some(name);
+    }
+    if n_shorts > 0 {
+	warn!("{} components matched to several nets, see {}",n_shorts,path);
+    }
+    Ok((names,n_shorts))
+}
+
+#[cfg(test)]
+#[test]
+fn test_resolve() {
+    use crate::{config::Config,matching};
+
+    let dir = std::env::temp_dir().join(format!("capest-shorts-{}",std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("shorts.txt");
+    let path = path.to_str().unwrap();
+    // Component 0 is hit by A and by B, whose flash point comes first
+    // in the file although listed last; component 1 only by C
+    let hit = |icom:usize,name:&str,x:Real,rank:usize| Hit { icom,name:name.to_string(),x,y:0.0,rank };
+    let hits = vec![hit(0,"A",1.0,5),hit(1,"C",3.0,3),hit(0,"A",2.0,7),hit(0,"B",4.0,2)];
+    let name = |u:&str| Some(u.to_string());
+
+    for policy in [ShortPolicy::Error,ShortPolicy::KeepFirst] {
+	let (names,n) = resolve(3,&hits,policy,path).unwrap();
+	assert_eq!(names,vec![name("B"),name("C"),None]);
+	assert_eq!(n,1);
+    }
+    assert_eq!(std::fs::read_to_string(path).unwrap().lines().skip(1).collect::<Vec<_>>(),
+	       vec!["component 1 named B","  B 4,0","  A 1,0 2,0"]);
+    let (names,n) = resolve(3,&hits,ShortPolicy::Ambiguous,path).unwrap();
+    assert_eq!(names,vec![name("A|B"),name("C"),None]);
+    assert_eq!(n,1);
+
+    // Only the error policy stops the extraction
+    let config = |policy:&str| -> Config { ron::from_str(&format!(
+	"(input:\"\",output:{:?},layers:[],roi:None,mark:None,\
+	  origin:(x:0.0,y:0.0),dpi:254.0,eps_rel:4.0,thickness:0.1,cap_min:0.0,\
+	  shorts:{})",dir,policy)).unwrap() };
+    assert!(matching::check(&config("Error"),&[],&[],1).is_err());
+    assert!(matching::check(&config("Error"),&[],&[],0).is_ok());
+    assert!(matching::check(&config("KeepFirst"),&[],&[],1).is_ok());
+    assert!(matching::check(&config("Ambiguous"),&[],&[],1).is_ok());
+}